@@ -0,0 +1,333 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{Error, Result},
+    format::{TrackData, encoder::Encoder},
+    rules::{Policy, TrackAction},
+    utils::SyncList,
+};
+
+/// Bitrates tried in turn when lowering the bitrate to fit a budget.
+const FIT_BITRATES: &[u32] = &[320, 256, 224, 192, 160, 128, 112, 96, 80, 64, 48, 32];
+
+/// What albums are ranked by when the library doesn't fit into the size budget, in order of precedence.
+#[derive(Debug, Clone, Copy, ValueEnum, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Priority {
+    /// The order of the sync list, albums missing from it coming last.
+    SyncList,
+    /// The average of the `RATING`/`FMPS_RATING` tags, highest first.
+    Rating,
+    /// The sum of the `PLAY_COUNT`/`FMPS_PLAYCOUNT` tags, highest first.
+    PlayCount,
+    /// The latest modification time of the album's files, newest first.
+    Recent,
+}
+
+impl Priority {
+    pub fn defaults() -> Vec<Priority> {
+        vec![
+            Priority::SyncList,
+            Priority::Rating,
+            Priority::PlayCount,
+            Priority::Recent,
+        ]
+    }
+}
+
+/// The tracks of a source folder, kept or left out of a sync as a whole.
+#[derive(Debug)]
+pub struct Album {
+    pub dir: PathBuf,
+    /// Tracks with the size of their source file.
+    tracks: Vec<(PathBuf, TrackData, u64)>,
+    sync_list_position: Option<usize>,
+    modified: Option<SystemTime>,
+}
+
+impl Album {
+    /// Groups tracks by their folder, sorted by path.
+    pub fn group(tracks: Vec<(PathBuf, TrackData)>, sync_list: Option<&SyncList>) -> Result<Vec<Album>> {
+        let mut albums = BTreeMap::<PathBuf, Album>::new();
+
+        for (file, track) in tracks {
+            let metadata = fs::metadata(&file).map_err(|e| Error::from(e).with_context(file.to_string_lossy()))?;
+            let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+            let album = albums.entry(dir.clone()).or_insert_with(|| Album {
+                dir,
+                tracks: vec![],
+                sync_list_position: None,
+                modified: None,
+            });
+
+            let position = sync_list.and_then(|x| x.position(&file));
+            album.sync_list_position = match (album.sync_list_position, position) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            album.modified = album.modified.max(metadata.modified().ok());
+            album.tracks.push((file, track, metadata.len()));
+        }
+
+        Ok(albums.into_values().collect())
+    }
+
+    pub fn into_tracks(self) -> impl Iterator<Item = (PathBuf, TrackData)> {
+        self.tracks.into_iter().map(|(file, track, _)| (file, track))
+    }
+
+    /// The estimated size of the album on the target, see [`estimate_size`].
+    pub fn size(&self, source_dir: &Path, policy: &Policy) -> u64 {
+        self.tracks
+            .iter()
+            .map(|(file, track, size)| {
+                let rel_path = file.strip_prefix(source_dir).unwrap_or(file);
+                estimate_size(&policy.action(rel_path, track), policy, track, *size)
+            })
+            .sum()
+    }
+
+    /// The average rating of the rated tracks, between 0 and 1.
+    fn rating(&self) -> Option<f64> {
+        let ratings = self
+            .tracks
+            .iter()
+            .filter_map(|(_, track, _)| {
+                let fmps = track.tag("fmps_rating").and_then(|x| x.first()?.parse::<f64>().ok());
+                let rating = track.tag("rating").and_then(|x| x.first()?.parse::<f64>().ok());
+
+                // Ratings come as stars, percentages or POPM bytes depending on the tagger.
+                fmps.or_else(|| {
+                    rating.map(|x| match x {
+                        x if x <= 5.0 => x / 5.0,
+                        x if x <= 100.0 => x / 100.0,
+                        x => x / 255.0,
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        (!ratings.is_empty()).then(|| ratings.iter().sum::<f64>() / ratings.len() as f64)
+    }
+
+    fn play_count(&self) -> u64 {
+        self.tracks
+            .iter()
+            .filter_map(|(_, track, _)| {
+                ["play_count", "playcount", "fmps_playcount"]
+                    .iter()
+                    .find_map(|key| track.tag(key)?.first()?.parse::<f64>().ok())
+            })
+            .map(|x| x as u64)
+            .sum()
+    }
+}
+
+/// Estimates the size of a track on the target, in bytes.
+///
+/// Lossy outputs are estimated from the duration and the bitrate of the encoder, lossless outputs from the size of the
+/// source, scaled down by any resampling. Tracks synced as is keep the size of their source.
+pub fn estimate_size(action: &TrackAction, policy: &Policy, track: &TrackData, source_size: u64) -> u64 {
+    match action {
+        TrackAction::Skip => 0,
+        TrackAction::Passthrough => source_size,
+        TrackAction::Transcode(encoder) => match (encoder.nominal_bitrate(), track.duration) {
+            (Some(bitrate), Some(duration)) => (duration * bitrate as f64 * 1000.0 / 8.0) as u64,
+            (Some(_), None) => source_size,
+            (None, _) => {
+                let resample = policy.resample(encoder, track);
+                let ratio = |output: Option<u32>, input: Option<u32>| match (output, input) {
+                    (Some(output), Some(input)) if input > 0 => output as f64 / input as f64,
+                    _ => 1.0,
+                };
+                let rate_ratio = ratio(resample.and_then(|x| x.sample_rate), track.sample_rate);
+                let depth_ratio = ratio(resample.and_then(|x| x.bit_depth), track.bit_depth);
+
+                (source_size as f64 * rate_ratio.min(1.0) * depth_ratio.min(1.0)) as u64
+            }
+        },
+    }
+}
+
+/// Sorts albums by `priorities`, earlier ones taking precedence. Ties keep their order.
+pub fn rank(albums: &mut [Album], priorities: &[Priority]) {
+    albums.sort_by(|a, b| {
+        priorities
+            .iter()
+            .map(|priority| match priority {
+                Priority::SyncList => match (a.sync_list_position, b.sync_list_position) {
+                    (Some(a), Some(b)) => a.cmp(&b),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                },
+                Priority::Rating => b.rating().partial_cmp(&a.rating()).unwrap_or(Ordering::Equal),
+                Priority::PlayCount => b.play_count().cmp(&a.play_count()),
+                Priority::Recent => b.modified.cmp(&a.modified),
+            })
+            .find(|x| x.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+}
+
+/// Takes ranked albums in turn while they fit into `max_size`, returning the kept albums and the left out ones with
+/// their estimated sizes.
+///
+/// Albums too large for the remaining space are passed over for smaller ones ranked below them.
+pub fn fit(albums: Vec<Album>, source_dir: &Path, policy: &Policy, max_size: u64) -> (Vec<Album>, Vec<(Album, u64)>) {
+    let mut used = 0;
+    let mut kept = vec![];
+    let mut left_out = vec![];
+
+    for album in albums {
+        let size = album.size(source_dir, policy);
+        if used + size <= max_size {
+            used += size;
+            kept.push(album);
+        } else {
+            left_out.push((album, size));
+        }
+    }
+
+    (kept, left_out)
+}
+
+/// Picks the highest bitrate up to the one of `encoder` at which every album fits into `max_size`, or the lowest one
+/// tried when none does.
+///
+/// `policy` builds the policy transcoding with a given encoder, so that rules inheriting it are lowered as well.
+pub fn fit_bitrate<F>(
+    encoder: &Encoder,
+    albums: &[Album],
+    source_dir: &Path,
+    max_size: u64,
+    policy: F,
+) -> Result<Policy>
+where
+    F: Fn(Encoder) -> Result<Policy>,
+{
+    let nominal = encoder.nominal_bitrate().ok_or_else(|| {
+        Error::descriptive("Fitting the bitrate requires a lossy codec").with_context(format!("{:?}", encoder.codec()))
+    })?;
+
+    let candidates = std::iter::once(*encoder).chain(
+        FIT_BITRATES
+            .iter()
+            .filter(|x| **x < nominal)
+            .filter_map(|x| encoder.with_bitrate(*x).ok()),
+    );
+
+    let mut fitted = None;
+    for candidate in candidates {
+        let candidate = policy(candidate)?;
+        let size = albums.iter().map(|x| x.size(source_dir, &candidate)).sum::<u64>();
+        let fits = size <= max_size;
+
+        fitted = Some(candidate);
+        if fits {
+            break;
+        }
+    }
+
+    fitted.map_or_else(|| policy(*encoder), Ok)
+}
+
+/// Formats a size in bytes with decimal units, e.g. `1.23 GB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for x in UNITS {
+        if size < 1000.0 {
+            break;
+        }
+
+        size /= 1000.0;
+        unit = x;
+    }
+
+    if unit == "B" {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.2} {unit}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Album, Priority, fit, format_size, rank};
+    use crate::{
+        format::{Codec, SourceFormat, TrackData, encoder::Encoder},
+        rules::Policy,
+    };
+    use std::path::PathBuf;
+
+    fn album(dir: &str, minutes: f64, rating: Option<&str>, sync_list_position: Option<usize>) -> Album {
+        let mut track = TrackData {
+            sample_rate: Some(44100),
+            bit_depth: Some(16),
+            duration: Some(minutes * 60.0),
+            ..TrackData::from_format(SourceFormat::Flac)
+        };
+        if let Some(rating) = rating {
+            track.tags.insert("rating".to_string(), vec![rating.to_string()]);
+        }
+
+        Album {
+            dir: PathBuf::from(dir),
+            tracks: vec![(PathBuf::from(dir).join("01.flac"), track, 30_000_000)],
+            sync_list_position,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn fits_albums_by_priority() {
+        let encoder = Encoder::new(Codec::Opus, Some(128), None, None).expect("encoder should be valid");
+        let policy = Policy::new(
+            vec![],
+            Some(encoder),
+            Default::default(),
+            vec![SourceFormat::Flac],
+            vec![],
+        )
+        .expect("policy should be valid");
+
+        // 128 kbps for 10 minutes is 9.6 MB.
+        let mut albums = vec![
+            album("a", 10.0, Some("3"), None),
+            album("b", 20.0, Some("5"), None),
+            album("c", 10.0, None, Some(0)),
+        ];
+        assert_eq!(albums[0].size("".as_ref(), &policy), 9_600_000);
+
+        rank(&mut albums, &Priority::defaults());
+        let dirs = albums
+            .iter()
+            .map(|x| x.dir.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(dirs, ["c", "b", "a"]);
+
+        // `b` doesn't fit next to `c`, leaving room for `a`.
+        let (kept, left_out) = fit(albums, "".as_ref(), &policy, 20_000_000);
+        let dirs = kept
+            .iter()
+            .map(|x| x.dir.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(dirs, ["c", "a"]);
+        assert_eq!(left_out[0].1, 19_200_000);
+
+        assert_eq!(format_size(19_200_000), "19.20 MB");
+        assert_eq!(format_size(512), "512 B");
+    }
+}
@@ -1,10 +1,19 @@
+use std::path::PathBuf;
+
 use clap::{
     Parser, Subcommand,
     builder::styling::{AnsiColor, Color, Style},
 };
 use clap_complete::Shell;
 
-use crate::commands::sync::SyncOpts;
+use crate::commands::{
+    doctor::DoctorOpts,
+    probe::ProbeOpts,
+    prune::PruneOpts,
+    pull::PullOpts,
+    status::StatusOpts,
+    sync::{SyncCommandOpts, SyncOpts},
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None, styles = get_styles())]
@@ -12,18 +21,48 @@ use crate::commands::sync::SyncOpts;
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    #[arg(long, global = true)]
+    /// The configuration file to read profiles from. Defaults to `$XDG_CONFIG_HOME/tsync/config.toml`.
+    pub config: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
     /// Syncs a music library to an ADB-connected Android device.
-    Sync(SyncOpts),
+    Sync(Box<SyncCommandOpts>),
+    /// Compares the library with the target without syncing, taking the same options as `sync`.
+    Status(Box<StatusOpts>),
+    /// Removes orphaned files, duplicate outputs, partial uploads and empty directories from the target.
+    Prune(Box<PruneOpts>),
+    /// Copies files from the device back to the host, such as playlists created or recordings made on the device.
+    Pull(Box<PullOpts>),
+    /// Lists the connected ADB devices with their state, Android version and storage volumes.
+    Devices,
+    /// Shows the codec, audio properties and tags read from files, and what a sync would do with them.
+    Probe(Box<ProbeOpts>),
+    /// Checks the tools, device and directories a sync depends on, taking the same options as `sync`.
+    ///
+    /// To check that the target is writable, a small `.tsync-doctor-*` file is copied into it, or into its nearest
+    /// existing parent, and removed again.
+    Doctor(Box<DoctorOpts>),
+    /// Inspects the configuration file.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
     Completion {
         #[arg(value_enum)]
         shell: Shell,
     },
 }
 
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Prints the sync options after merging the command line, the selected profile and the defaults.
+    Show(Box<SyncOpts>),
+}
+
 const fn get_styles() -> clap::builder::Styles {
     clap::builder::Styles::styled()
         .usage(
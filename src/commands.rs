@@ -1 +1,8 @@
+pub mod config;
+pub mod devices;
+pub mod doctor;
+pub mod probe;
+pub mod prune;
+pub mod pull;
+pub mod status;
 pub mod sync;
@@ -0,0 +1,9 @@
+use crate::{commands::sync::SyncOpts, config::Config, errors::Result};
+
+/// Prints the options a sync would run with, after layering them over the profile and the defaults.
+pub fn show(opts: SyncOpts, config: &Config) -> Result<()> {
+    let opts = opts.resolve(config)?;
+    print!("{}", toml::to_string_pretty(&opts)?);
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use colored::*;
+
+use crate::{budget::format_size, errors::Result, utils::fs::FSBackend};
+
+/// Lists the connected ADB devices with their storage volumes, to pick the serial and target path to sync to.
+pub fn run() -> Result<()> {
+    let devices = FSBackend::adb_devices()?;
+    if devices.is_empty() {
+        println!(
+            "No ADB devices connected. Enable USB debugging on the device and plug it in, or `adb connect` to it."
+        );
+        return Ok(());
+    }
+
+    for (i, device) in devices.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+
+        let state = match device.state.as_str() {
+            "device" => device.state.green(),
+            _ => device.state.red(),
+        };
+        match &device.model {
+            Some(model) => println!("{} {} {}", device.serial.bold(), state, model),
+            None => println!("{} {}", device.serial.bold(), state),
+        }
+
+        match device.state.as_str() {
+            "device" => {}
+            "unauthorized" => {
+                println!("  Allow USB debugging in the prompt shown on the device");
+                continue;
+            }
+            _ => {
+                println!("  Reconnect the device, or restart the ADB server with `adb kill-server`");
+                continue;
+            }
+        }
+
+        // Devices can drop off while being listed, which shouldn't hide the ones after them.
+        let details = FSBackend::android_version(&device.serial)
+            .and_then(|version| Ok((version, FSBackend::volumes(&device.serial)?)));
+        let (version, volumes) = match details {
+            Ok(details) => details,
+            Err(e) => {
+                println!("  {}", e.to_string().red());
+                continue;
+            }
+        };
+
+        println!("  {:<10} {}", "Android:", version);
+        for volume in volumes {
+            let kind = if volume.removable { "SD card:" } else { "Internal:" };
+            let free_space = volume
+                .free_space
+                .map(|x| format!("{} free", format_size(x)))
+                .unwrap_or_default();
+            println!("  {:<10} {} {}", kind, volume.path.display(), free_space.dimmed());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,407 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs, io,
+    path::Path,
+    process::{Command, Output},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Args, ValueEnum};
+use colored::*;
+
+use crate::{
+    budget::format_size,
+    commands::sync::{self, SyncOpts},
+    config::Config,
+    errors::{Error, Result},
+    format::Codec,
+    utils::fs::FSBackend,
+};
+
+/// Free space of the temp directory below which a warning is shown, as transcodes are staged there.
+const MIN_TEMP_SPACE: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Args)]
+pub struct DoctorOpts {
+    #[command(flatten)]
+    sync: SyncOpts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Failure,
+}
+
+/// Prints the outcome of each check with a fix for the ones that didn't pass, counting the problems found.
+#[derive(Debug, Default)]
+struct Report {
+    warnings: usize,
+    failures: usize,
+}
+
+impl Report {
+    fn add(&mut self, status: Status, message: impl Display, fix: &str) {
+        let label = match status {
+            Status::Ok => " ok ".green(),
+            Status::Warning => {
+                self.warnings += 1;
+                "warn".yellow()
+            }
+            Status::Failure => {
+                self.failures += 1;
+                "fail".red()
+            }
+        };
+
+        println!("[{label}] {message}");
+        if status != Status::Ok && !fix.is_empty() {
+            println!("       {}", fix.dimmed());
+        }
+    }
+
+    fn ok(&mut self, message: impl Display) {
+        self.add(Status::Ok, message, "");
+    }
+}
+
+/// Checks the tools, device and directories a sync with the same options depends on, printing how to fix what's
+/// missing.
+pub fn run(opts: DoctorOpts, config: &Config) -> Result<()> {
+    let sync = opts.sync.resolve(config)?;
+    let mut report = Report::default();
+
+    check_ffmpeg(&mut report, sync.codec());
+    check_opusenc(&mut report, sync.codec());
+
+    let fs = sync.fs();
+    let backend_ready = match fs {
+        FSBackend::Adb => check_adb(&mut report, sync.serial()),
+        FSBackend::Ftp => {
+            report.add(
+                Status::Failure,
+                "The FTP backend is not implemented yet",
+                "Use `--fs adb` instead",
+            );
+            false
+        }
+        FSBackend::None => true,
+    };
+
+    match sync.target() {
+        Some(target) if backend_ready => check_target(&mut report, &fs, target),
+        Some(_) => {}
+        None => report.add(
+            Status::Warning,
+            "No target directory was given, skipping the target checks",
+            "Pass a target directory or a profile with `--profile`",
+        ),
+    }
+
+    check_temp_dir(&mut report);
+
+    println!();
+    let summary = format!(
+        "{} problems and {} warnings found",
+        report.failures.to_string().red(),
+        report.warnings.to_string().yellow()
+    );
+    match (report.failures, report.warnings) {
+        (0, 0) => println!("{}", "No problems found".green()),
+        (0, _) => println!("{summary}"),
+        // Failing lets scripts check the setup before syncing.
+        _ => return Err(Error::descriptive(summary)),
+    }
+
+    Ok(())
+}
+
+/// Runs a tool, returning `None` if it isn't installed.
+fn run_tool(program: &str, args: &[&str]) -> io::Result<Option<Output>> {
+    match Command::new(program).args(args).output() {
+        Ok(output) => Ok(Some(output)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn check_ffmpeg(report: &mut Report, codec: Option<Codec>) {
+    let output = match run_tool("ffmpeg", &["-hide_banner", "-encoders"]) {
+        Ok(Some(output)) if output.status.success() => output,
+        Ok(Some(output)) => {
+            let message = format!("ffmpeg exited with code {}", output.status.code().unwrap_or(-1));
+            report.add(Status::Failure, message, "Reinstall ffmpeg");
+            return;
+        }
+        Ok(None) => {
+            let fix = "Install ffmpeg with your package manager or from https://ffmpeg.org and add it to the PATH";
+            report.add(Status::Failure, "ffmpeg was not found", fix);
+            return;
+        }
+        Err(e) => {
+            report.add(Status::Failure, format!("ffmpeg could not be run: {e}"), "");
+            return;
+        }
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let encoders = parse_encoders(&stdout);
+    let (available, missing): (Vec<&Codec>, Vec<&Codec>) = Codec::value_variants()
+        .iter()
+        .partition(|x| encoders.contains(x.ffmpeg_lib()));
+
+    if !available.is_empty() {
+        let libs = available.iter().map(|x| x.ffmpeg_lib()).collect::<Vec<_>>();
+        report.ok(format!("ffmpeg can encode with {}", libs.join(", ")));
+    }
+
+    for missing in missing {
+        // Only the codec synced to is needed, the others matter for rules or other profiles.
+        let status = match codec {
+            Some(codec) if codec == *missing => Status::Failure,
+            _ => Status::Warning,
+        };
+        let message = format!("ffmpeg lacks the {} encoder for {:?}", missing.ffmpeg_lib(), missing);
+        let fix = format!(
+            "Install an ffmpeg build compiled with --enable-{}, like the full builds most distributions ship",
+            missing.ffmpeg_lib()
+        );
+        report.add(status, message, &fix);
+    }
+}
+
+fn check_opusenc(report: &mut Report, codec: Option<Codec>) {
+    match run_tool("opusenc", &["--version"]) {
+        Ok(Some(output)) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            report.ok(stdout.lines().next().unwrap_or("opusenc").trim());
+        }
+        Ok(Some(output)) => {
+            let message = format!("opusenc exited with code {}", output.status.code().unwrap_or(-1));
+            report.add(Status::Failure, message, "Reinstall opus-tools");
+        }
+        Ok(None) => {
+            // FLAC and WAV sources are always encoded into Opus with opusenc.
+            let status = match codec {
+                Some(Codec::Opus) => Status::Failure,
+                _ => Status::Warning,
+            };
+            let fix = "Install opus-tools with your package manager, it encodes FLAC and WAV sources into Opus";
+            report.add(status, "opusenc was not found", fix);
+        }
+        Err(e) => report.add(Status::Failure, format!("opusenc could not be run: {e}"), ""),
+    }
+}
+
+/// Checks that exactly one authorized device is connected, or the one with `serial`. Returns whether it is.
+fn check_adb(report: &mut Report, serial: Option<&str>) -> bool {
+    let devices = match FSBackend::adb_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            report.add(Status::Failure, e, "");
+            return false;
+        }
+    };
+
+    let devices = devices
+        .into_iter()
+        .filter(|x| serial.is_none_or(|serial| x.serial == serial))
+        .collect::<Vec<_>>();
+
+    let device = match (devices.as_slice(), serial) {
+        ([], Some(serial)) => {
+            let fix = "Check the serial against `tsync devices`, and that the device is plugged in";
+            report.add(Status::Failure, format!("Device {serial} is not connected"), fix);
+            return false;
+        }
+        ([], None) => {
+            let fix = "Enable USB debugging in the developer options and plug the device in, or `adb connect` to it";
+            report.add(Status::Failure, "No ADB device is connected", fix);
+            return false;
+        }
+        ([device], _) => device,
+        (devices, _) => {
+            let fix = "Pass `--serial` with one of the serials listed by `tsync devices`";
+            report.add(
+                Status::Failure,
+                format!("{} ADB devices are connected", devices.len()),
+                fix,
+            );
+            return false;
+        }
+    };
+
+    let name = match &device.model {
+        Some(model) => format!("{} ({model})", device.serial),
+        None => device.serial.clone(),
+    };
+    match device.state.as_str() {
+        "device" => {
+            report.ok(format!("Device {name} is connected"));
+            FSBackend::select_device(&device.serial);
+            true
+        }
+        "unauthorized" => {
+            let fix = "Allow USB debugging in the prompt shown on the device. If none shows, revoke the USB debugging \
+                       authorizations in the developer options and reconnect";
+            report.add(Status::Failure, format!("Device {name} is unauthorized"), fix);
+            false
+        }
+        state => {
+            let fix = "Reconnect the device, or restart the ADB server with `adb kill-server`";
+            report.add(Status::Failure, format!("Device {name} is {state}"), fix);
+            false
+        }
+    }
+}
+
+/// Checks that a file can be written to the target, or its nearest existing parent when it doesn't exist yet.
+fn check_target(report: &mut Report, fs: &FSBackend, target: &Path) {
+    let mut existing = None;
+    for path in target.ancestors().filter(|x| !x.as_os_str().is_empty()) {
+        match fs.exists(path) {
+            Ok(true) => {
+                existing = Some(path);
+                break;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                report.add(
+                    Status::Failure,
+                    format!("{} could not be checked: {e}", path.display()),
+                    "",
+                );
+                return;
+            }
+        }
+    }
+    let Some(dir) = existing else {
+        report.add(
+            Status::Failure,
+            format!("No parent of the target {} exists", target.display()),
+            "Check the target path against the volumes listed by `tsync devices`",
+        );
+        return;
+    };
+
+    // Unique, so that a file of the same name already on the target is never removed.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or_default();
+    let name = format!(".tsync-doctor-{}-{nanos}", std::process::id());
+    let probe = sync::temp_dir().join(&name);
+    let target_probe = dir.join(&name);
+    let written = fs::create_dir_all(sync::temp_dir())
+        .and_then(|_| fs::write(&probe, b""))
+        .map_err(Into::into)
+        .and_then(|_| fs.cp(&probe, &target_probe));
+    let _ = fs::remove_file(&probe);
+
+    if written.is_ok()
+        && let Err(e) = fs.remove(&target_probe)
+    {
+        report.add(
+            Status::Warning,
+            format!("The test file {} could not be removed: {e}", target_probe.display()),
+            "Remove it by hand",
+        );
+    }
+
+    match written {
+        Ok(()) => {
+            let free_space = fs
+                .free_space(target)
+                .ok()
+                .flatten()
+                .map(|x| format!(", {} free", format_size(x)))
+                .unwrap_or_default();
+            report.ok(format!("The target {} is writable{free_space}", dir.display()));
+        }
+        Err(e) => {
+            let fix = "Write to shared storage like /sdcard/Music, app directories under /sdcard/Android are off limits \
+                       since Android 11";
+            report.add(
+                Status::Failure,
+                format!("The target {} is not writable: {e}", dir.display()),
+                fix,
+            );
+        }
+    }
+}
+
+fn check_temp_dir(report: &mut Report) {
+    let temp_dir = sync::temp_dir();
+    let probe = temp_dir.join(".tsync-doctor");
+    let written = fs::create_dir_all(&temp_dir)
+        .and_then(|_| fs::write(&probe, b""))
+        .and_then(|_| fs::remove_file(&probe));
+
+    if let Err(e) = written {
+        let fix = "Point TMPDIR at a writable directory";
+        report.add(
+            Status::Failure,
+            format!("The temp directory {} is not writable: {e}", temp_dir.display()),
+            fix,
+        );
+        return;
+    }
+
+    match FSBackend::None.free_space(&temp_dir).ok().flatten() {
+        Some(free_space) if free_space < MIN_TEMP_SPACE => {
+            let fix = "Transcodes are staged there before being copied, free up space or point TMPDIR at a larger disk";
+            report.add(
+                Status::Warning,
+                format!(
+                    "The temp directory {} has only {} free",
+                    temp_dir.display(),
+                    format_size(free_space)
+                ),
+                fix,
+            );
+        }
+        Some(free_space) => report.ok(format!(
+            "The temp directory {} is writable, {} free",
+            temp_dir.display(),
+            format_size(free_space)
+        )),
+        None => report.ok(format!("The temp directory {} is writable", temp_dir.display())),
+    }
+}
+
+/// Reads the encoder names from the output of `ffmpeg -encoders`, listed after a `------` separator.
+fn parse_encoders(output: &str) -> HashSet<&str> {
+    output
+        .lines()
+        .skip_while(|x| !x.trim_start().starts_with("------"))
+        .skip(1)
+        .filter_map(|x| x.split_whitespace().nth(1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_encoders;
+
+    #[test]
+    fn parses_ffmpeg_encoders() {
+        let encoders = parse_encoders(
+            "Encoders:
+ V..... = Video
+ A..... = Audio
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10 (codec h264)
+ A....D aac                  AAC (Advanced Audio Coding)
+ A....D flac                 FLAC (Free Lossless Audio Codec)
+ A....D libopus              libopus Opus (codec opus)
+",
+        );
+
+        assert!(encoders.contains("libopus"));
+        assert!(encoders.contains("aac"));
+        assert!(!encoders.contains("Audio"));
+        assert!(!encoders.contains("libmp3lame"));
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, ValueEnum};
+use colored::*;
+use serde::Serialize;
+
+use crate::{
+    commands::sync::{SyncOpts, TrackPlan},
+    config::Config,
+    errors::{Error, Result},
+    format::{
+        SourceFormat, TrackData,
+        container::{CONTAINERS, Container},
+        encoder::Encoder,
+        get_track_data,
+    },
+    rules::Policy,
+    utils::{
+        fs::read_dir_recursively,
+        ignore::Ignore,
+        path::{PathExtensions, normalize_path},
+    },
+};
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum ProbeFormat {
+    /// A row per file, followed by its tags with `--tags`.
+    #[default]
+    Table,
+    /// An array of objects holding everything read from each file, tags included.
+    Json,
+}
+
+#[derive(Debug, Clone, Args)]
+// The files to probe take the place of the source and target arguments, which are given as options instead.
+#[command(
+    mut_arg("source", |x| x.long("source").value_name("DIR")),
+    mut_arg("target", |x| x.long("target").value_name("DIR"))
+)]
+pub struct ProbeOpts {
+    /// The files to probe. Directories are searched recursively for audio files.
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+
+    #[command(flatten)]
+    sync: SyncOpts,
+
+    #[arg(long, value_enum, default_value_t)]
+    /// How to print the results.
+    format: ProbeFormat,
+
+    #[arg(long)]
+    /// Lists the tags of each file in the table.
+    tags: bool,
+}
+
+/// What was read from a file and what a sync would do with it.
+#[derive(Debug, Serialize)]
+struct Probe {
+    path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    container: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    codec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bit_depth: Option<u32>,
+    /// In seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+    /// In kbps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bitrate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio_tracks: Option<usize>,
+    tags: BTreeMap<String, Vec<String>>,
+    /// `passthrough`, `transcode`, `split` for album images cut along their CUE sheet, `skip`, or `error` when the file
+    /// couldn't be read.
+    action: String,
+    /// The codec and nominal bitrate of a transcode, e.g. `opus 128K`, followed by the track count of a split.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    /// The rule or codec list deciding the action, or the error reading the file.
+    reason: String,
+}
+
+impl Probe {
+    fn new(path: PathBuf, source_dir: Option<&Path>, policy: &Policy, split_cue: bool) -> Probe {
+        let extension = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default()
+            .to_string();
+        let container = container_of(&extension).map(|x| format!("{x:?}").to_lowercase());
+
+        let error = |path: PathBuf, container: Option<String>, e: Error| Probe {
+            path,
+            container,
+            codec: None,
+            sample_rate: None,
+            bit_depth: None,
+            duration: None,
+            bitrate: None,
+            audio_tracks: None,
+            tags: BTreeMap::new(),
+            action: "error".to_string(),
+            output: None,
+            reason: e.to_string(),
+        };
+        let track = match get_track_data(&path, &extension) {
+            Ok(track) => track,
+            Err(e) => return error(path, container, e),
+        };
+
+        // Rules match paths relative to the source directory, files outside of it are matched by their name.
+        let abs_path = std::path::absolute(&path)
+            .map(|x| normalize_path(&x))
+            .unwrap_or_default();
+        let rel_path = source_dir
+            .and_then(|x| abs_path.strip_prefix(x).ok())
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from(path.get_file_name()));
+
+        let output = |encoder: &Encoder| match encoder.nominal_bitrate() {
+            Some(bitrate) => format!("{} {bitrate}K", value_name(&encoder.codec())),
+            None => value_name(&encoder.codec()),
+        };
+        let plan = match TrackPlan::new(&path, &rel_path, &track, policy.action(&rel_path, &track), split_cue) {
+            Ok(plan) => plan,
+            Err(e) => return error(path, container, e),
+        };
+        let (action, output) = match plan {
+            TrackPlan::Passthrough(_) => ("passthrough", None),
+            TrackPlan::Transcode(encoder, _) => ("transcode", Some(output(&encoder))),
+            TrackPlan::Split(encoder, segments) => (
+                "split",
+                Some(format!("{}, {} tracks", output(&encoder), segments.len())),
+            ),
+            TrackPlan::Skip => ("skip", None),
+        };
+        let reason = policy.reason(&rel_path, &track);
+        let TrackData {
+            format,
+            tags,
+            sample_rate,
+            bit_depth,
+            duration,
+            bitrate,
+            track_count,
+            ..
+        } = track;
+
+        Probe {
+            path,
+            container,
+            codec: Some(value_name::<SourceFormat>(&format)),
+            sample_rate,
+            bit_depth,
+            duration,
+            bitrate,
+            audio_tracks: Some(track_count),
+            tags: tags.into_iter().collect(),
+            action: action.to_string(),
+            output,
+            reason,
+        }
+    }
+}
+
+/// Shows what tsync reads from audio files and what a sync with the given options would do with them.
+pub fn run(opts: ProbeOpts, config: &Config) -> Result<()> {
+    let sync = opts.sync.resolve(config)?;
+    let policy = sync.policy()?;
+    let source_dir = sync
+        .source()
+        .map(std::path::absolute)
+        .transpose()?
+        .map(|x| normalize_path(&x));
+
+    let extensions = CONTAINERS
+        .iter()
+        .flat_map(|(_, extensions, _)| extensions.iter().copied())
+        .collect::<Vec<_>>();
+    let mut files = vec![];
+    for path in &opts.paths {
+        if path.is_dir() {
+            let ignore = Ignore::new(path, &[])?;
+            let mut found = read_dir_recursively(path, &Some(extensions.clone()), &ignore)?;
+            found.sort();
+            files.extend(found);
+        } else if path.is_file() {
+            files.push(path.clone());
+        } else {
+            return Err(Error::descriptive("File does not exist").with_context(path.to_string_lossy()));
+        }
+    }
+
+    let probes = files
+        .into_iter()
+        .map(|x| Probe::new(x, source_dir.as_deref(), &policy, sync.split_cue()))
+        .collect::<Vec<_>>();
+
+    match opts.format {
+        ProbeFormat::Json => println!("{}", serde_json::to_string_pretty(&probes)?),
+        ProbeFormat::Table => print_table(&probes, opts.tags),
+    }
+
+    Ok(())
+}
+
+fn print_table(probes: &[Probe], tags: bool) {
+    let rows = probes
+        .iter()
+        .map(|probe| {
+            let duration = probe
+                .duration
+                .map(|x| format!("{}:{:02}", x as u64 / 60, x as u64 % 60))
+                .unwrap_or_default();
+
+            [
+                probe.path.display().to_string(),
+                probe.codec.clone().unwrap_or_default(),
+                probe.container.clone().unwrap_or_default(),
+                probe.sample_rate.map(|x| format!("{x} Hz")).unwrap_or_default(),
+                probe.bit_depth.map(|x| format!("{x}-bit")).unwrap_or_default(),
+                duration,
+                probe.bitrate.map(|x| format!("{x} kbps")).unwrap_or_default(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let header = ["Path", "Codec", "Container", "Rate", "Depth", "Duration", "Bitrate"];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: &[String]| {
+        cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+    let header = header.map(str::to_string);
+    println!("{}  {}", line(&header).bold(), "Action".bold());
+
+    for (probe, row) in probes.iter().zip(&rows) {
+        let action = match probe.action.as_str() {
+            "passthrough" => probe.action.green(),
+            "transcode" | "split" => probe.action.cyan(),
+            "skip" => probe.action.yellow(),
+            _ => probe.action.red(),
+        };
+        let output = probe.output.as_deref().map(|x| format!(" to {x}")).unwrap_or_default();
+        println!(
+            "{}  {action}{output} {}",
+            line(row),
+            format!("({})", probe.reason).dimmed()
+        );
+
+        if tags {
+            for (key, values) in &probe.tags {
+                println!("  {} {}", format!("{key}:").dimmed(), values.join("; "));
+            }
+        }
+    }
+}
+
+/// The container files with `extension` are read as, the first one listed in [`CONTAINERS`] when it is ambiguous.
+fn container_of(extension: &str) -> Option<Container> {
+    CONTAINERS
+        .iter()
+        .find(|(_, extensions, _)| extensions.iter().any(|x| x.eq_ignore_ascii_case(extension)))
+        .map(|(container, _, _)| *container)
+}
+
+/// The name a value is written as on the command line, e.g. `aac-lc`.
+fn value_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|x| x.get_name().to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::{
+    commands::{
+        status::{Comparison, compare},
+        sync::SyncOpts,
+    },
+    config::Config,
+    cue::CueSheet,
+    errors::Result,
+    utils::path::PathExtensions,
+};
+
+/// Extensions of interrupted copies left behind by file managers and transfer tools.
+const PARTIAL_EXTENSIONS: &[&str] = &["part", "partial", "tmp"];
+
+#[derive(Debug, Clone, Args)]
+pub struct PruneOpts {
+    #[command(flatten)]
+    sync: SyncOpts,
+
+    #[arg(long)]
+    /// Lists what would be removed without removing anything.
+    dry_run: bool,
+
+    #[arg(long, short)]
+    /// Removes without asking for confirmation.
+    yes: bool,
+}
+
+/// Removes files from the target that a sync with the same options would not write, and the directories left empty.
+///
+/// Files are only removed when no source file shares their path (ignoring the extension), so albums that are merely
+/// left out of the sync list or query are kept.
+pub fn run(opts: PruneOpts, config: &Config) -> Result<()> {
+    let sync = opts.sync.resolve(config)?;
+    let selection = sync.select()?;
+    let Comparison {
+        target_files,
+        in_sync,
+        extraneous,
+        ..
+    } = compare(&selection, sync.split_cue())?;
+    let (fs, source_dir, target_dir) = (&selection.fs, &selection.source_dir, &selection.target_dir);
+
+    let in_sync_stems = in_sync.iter().map(|x| x.with_extension("")).collect::<HashSet<_>>();
+    let mut source_stems = HashMap::<PathBuf, HashSet<OsString>>::new();
+    let mut has_source = |rel_path: &Path| {
+        let parent = rel_path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let stems = source_stems.entry(parent.clone()).or_insert_with(|| {
+            let files = std::fs::read_dir(source_dir.join(&parent))
+                .into_iter()
+                .flatten()
+                .filter_map(|x| Some(x.ok()?.path()))
+                .collect::<Vec<_>>();
+            // Tracks split from an album image are named after its CUE sheet rather than the image.
+            let segments = files
+                .iter()
+                .filter_map(|x| CueSheet::read_for(x).ok().flatten())
+                .flat_map(|(sheet, file)| sheet.segments(&file))
+                .map(|x| OsString::from(x.file_stem));
+
+            files
+                .iter()
+                .filter_map(|x| x.file_stem().map(|x| x.to_os_string()))
+                .chain(segments)
+                .collect()
+        });
+
+        rel_path.file_stem().is_some_and(|x| stems.contains(x))
+    };
+
+    // Older outputs of tracks whose current output is on the target as well, e.g. `.flac` next to `.opus`.
+    let (duplicates, rest): (Vec<_>, Vec<_>) = extraneous
+        .into_iter()
+        .partition(|x| in_sync_stems.contains(&x.with_extension("")));
+    let orphans = rest.into_iter().filter(|x| !has_source(x)).collect::<Vec<_>>();
+    let sidecars = orphans
+        .iter()
+        .map(|x| x.with_extension("lrc"))
+        .filter(|x| target_files.contains(x))
+        .collect::<Vec<_>>();
+    let mut partials = target_files
+        .iter()
+        .filter(|x| {
+            x.extension()
+                .and_then(|x| x.to_str())
+                .is_some_and(|ext| PARTIAL_EXTENSIONS.iter().any(|x| x.eq_ignore_ascii_case(ext)))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    partials.sort();
+
+    let removals = [&orphans, &sidecars, &duplicates, &partials]
+        .into_iter()
+        .flatten()
+        .collect::<HashSet<_>>();
+    let remaining = target_files
+        .iter()
+        .filter(|x| !removals.contains(x))
+        .collect::<Vec<_>>();
+
+    let mut empty_dirs = fs
+        .exists(target_dir)?
+        .then(|| fs.build_dir_list(target_dir))
+        .transpose()?
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|x| x.strip_prefix(target_dir).ok().map(Path::to_path_buf))
+        .filter(|dir| !remaining.iter().any(|x| x.starts_with(dir)))
+        .collect::<Vec<_>>();
+    // Children are removed before their parents.
+    empty_dirs.sort_by_key(|x| std::cmp::Reverse(x.components().count()));
+
+    let file_count = removals.len();
+    if file_count == 0 && empty_dirs.is_empty() {
+        println!("Nothing to prune");
+        return Ok(());
+    }
+
+    for (label, paths) in [
+        ("orphaned files without a source", &orphans),
+        ("lyrics of orphaned files", &sidecars),
+        ("duplicate outputs", &duplicates),
+        ("partial uploads", &partials),
+        ("empty directories", &empty_dirs),
+    ] {
+        if paths.is_empty() {
+            continue;
+        }
+
+        println!("{} {label}:", paths.len().to_string().yellow());
+        for path in paths {
+            println!("  {}", path.display().to_string().dimmed());
+        }
+    }
+
+    if opts.dry_run {
+        println!("Dry run, nothing was removed");
+        return Ok(());
+    }
+
+    if !opts.yes {
+        print!(
+            "Remove {} files and {} directories from the target? [y/N] ",
+            file_count,
+            empty_dirs.len()
+        );
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            println!("Nothing was removed");
+            return Ok(());
+        }
+    }
+
+    let indicator = {
+        let style = ProgressStyle::with_template("{msg}\n[{elapsed_precise}] [{wide_bar:.cyan/blue}] [{pos}/{len}]")
+            .unwrap_or_else(|_| ProgressStyle::default_bar())
+            .progress_chars("#>-");
+        ProgressBar::new((file_count + empty_dirs.len()) as u64).with_style(style)
+    };
+
+    for rel_path in removals {
+        indicator.set_message(format!("Removing {}", rel_path.get_file_name()));
+        fs.remove(&target_dir.join(rel_path))?;
+        indicator.inc(1);
+    }
+
+    for rel_path in &empty_dirs {
+        indicator.set_message(format!("Removing {}", rel_path.display()));
+        fs.rmdir(&target_dir.join(rel_path))?;
+        indicator.inc(1);
+    }
+
+    indicator.finish_with_message(format!(
+        "Removed {} files and {} directories",
+        file_count,
+        empty_dirs.len()
+    ));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PruneOpts, run};
+    use crate::config::Config;
+    use clap::Parser;
+    use std::{
+        fs,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        prune: PruneOpts,
+    }
+
+    #[test]
+    fn prunes_targets_with_spaces_and_dollar_signs() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time should be after unix epoch")
+            .as_nanos();
+        let root = std::env::temp_dir().join(format!("tsync-test-prune-{nanos}"));
+        let source = root.join("Library");
+        let target = root.join("My $HOME Music");
+        let orphan = target.join("Old Artist/Gone $1").join("01 Intro.opus");
+
+        fs::create_dir_all(&source).expect("source dir should be created");
+        fs::create_dir_all(orphan.parent().unwrap()).expect("album dir should be created");
+        fs::write(&orphan, b"").expect("orphan should be written");
+        fs::write(orphan.with_extension("lrc"), b"").expect("sidecar should be written");
+
+        let cli = Cli::parse_from([
+            "tsync".as_ref(),
+            "-f".as_ref(),
+            "none".as_ref(),
+            "--yes".as_ref(),
+            source.as_os_str(),
+            target.as_os_str(),
+        ]);
+        run(cli.prune, &Config::default()).expect("prune should succeed");
+
+        assert!(target.exists());
+        assert!(!target.join("Old Artist").exists());
+
+        let _ = fs::remove_dir_all(root);
+    }
+}
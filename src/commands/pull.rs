@@ -0,0 +1,124 @@
+use std::{fs, path::PathBuf};
+
+use clap::Args;
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::{
+    budget::format_size,
+    errors::{Error, Result},
+    utils::{
+        fs::{FSBackend, file_stat},
+        glob,
+        path::PathExtensions,
+    },
+};
+
+#[derive(Debug, Clone, Args)]
+pub struct PullOpts {
+    /// The directory on the device to pull from.
+    target: PathBuf,
+
+    /// The local directory to pull into.
+    dest: PathBuf,
+
+    #[arg(long, short, default_value = "adb")]
+    /// Specifies the filesystem backend to pull with.
+    fs: FSBackend,
+
+    #[arg(long, short)]
+    /// The serial of the ADB device to pull from, required when more than one device is connected.
+    serial: Option<String>,
+
+    #[arg(long, short, value_delimiter = ',')]
+    /// Glob patterns relative to the target of the files to pull, e.g. `Playlists/*.m3u8` or `Recordings/**`.
+    /// Every file is pulled when none is given.
+    include: Vec<String>,
+
+    #[arg(long, short = 'x', value_delimiter = ',')]
+    /// Glob patterns relative to the target of the files not to pull.
+    exclude: Vec<String>,
+}
+
+/// Copies files from the target back to a local directory, skipping the ones whose local copy has the same size and
+/// modification time.
+pub fn run(opts: PullOpts) -> Result<()> {
+    let PullOpts {
+        target,
+        dest,
+        fs: backend,
+        serial,
+        include,
+        exclude,
+    } = opts;
+
+    if let Some(serial) = &serial {
+        FSBackend::select_device(serial);
+    }
+
+    if !backend.available()? {
+        let message = format!("{backend:?} is not available! Make sure everything is right.");
+        return Err(Error::descriptive(message));
+    }
+
+    if !backend.exists(&target)? {
+        return Err(Error::descriptive("The target directory does not exist").with_context(target.to_string_lossy()));
+    }
+
+    let mut files = backend
+        .build_file_stats(&target)?
+        .into_iter()
+        .filter_map(|(path, stat)| {
+            let rel_path = path.strip_prefix(&target).ok()?.to_path_buf();
+            let pattern_path = rel_path.to_string_lossy().replace('\\', "/");
+            let included = include.is_empty() || include.iter().any(|x| glob::matches_path(x, &pattern_path));
+            let excluded = exclude.iter().any(|x| glob::matches_path(x, &pattern_path));
+
+            (included && !excluded).then_some((rel_path, stat))
+        })
+        .collect::<Vec<_>>();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let total = files.len();
+    let changed = files
+        .into_iter()
+        .filter(|(rel_path, stat)| {
+            fs::metadata(dest.join(rel_path))
+                .map(|x| file_stat(&x) != *stat)
+                .unwrap_or(true)
+        })
+        .collect::<Vec<_>>();
+
+    if changed.is_empty() {
+        println!("{} files matched, all up to date", total);
+        return Ok(());
+    }
+
+    let indicator = {
+        let style = ProgressStyle::with_template("{msg}\n[{elapsed_precise}] [{wide_bar:.cyan/blue}] [{pos}/{len}]")
+            .unwrap_or_else(|_| ProgressStyle::default_bar())
+            .progress_chars("#>-");
+        ProgressBar::new(changed.len() as u64).with_style(style)
+    };
+
+    let mut pulled_size = 0;
+    for (rel_path, stat) in &changed {
+        indicator.set_message(format!("Pulling {}", rel_path.get_file_name()));
+        let source = target.join(rel_path);
+        backend
+            .cp_from(&source, &dest.join(rel_path))
+            .map_err(|e| e.with_context(source.to_string_lossy()))?;
+        pulled_size += stat.size;
+        indicator.inc(1);
+    }
+
+    indicator.finish_with_message(format!(
+        "Pulled {} of {} files ({}), {} already up to date",
+        changed.len().to_string().green(),
+        total,
+        format_size(pulled_size),
+        total - changed.len()
+    ));
+
+    Ok(())
+}
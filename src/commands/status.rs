@@ -0,0 +1,229 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use colored::*;
+
+use crate::{
+    commands::sync::{Selection, SyncOpts, TrackPlan},
+    config::Config,
+    errors::{Error, Result},
+    format::container::CONTAINERS,
+    utils::sync_list_entry,
+};
+
+#[derive(Debug, Clone, Args)]
+pub struct StatusOpts {
+    #[command(flatten)]
+    sync: SyncOpts,
+
+    #[arg(long, value_name = "FILE")]
+    /// Writes the tracks missing from the target to a sync list, to sync only them with `--sync-list`.
+    missing_list: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Lists every album in the breakdown, including the ones fully in sync.
+    all: bool,
+}
+
+/// How the tracks of an album compare with the target.
+#[derive(Debug, Default)]
+struct AlbumStatus {
+    in_sync: usize,
+    missing: usize,
+    /// Tracks present on the target with another output format, e.g. after changing the codec.
+    outdated: usize,
+    /// Audio files on the target that no selected track is written to.
+    extraneous: usize,
+}
+
+impl AlbumStatus {
+    fn add(&mut self, other: &AlbumStatus) {
+        self.in_sync += other.in_sync;
+        self.missing += other.missing;
+        self.outdated += other.outdated;
+        self.extraneous += other.extraneous;
+    }
+
+    fn is_in_sync(&self) -> bool {
+        self.missing == 0 && self.outdated == 0 && self.extraneous == 0
+    }
+}
+
+/// The files on the target compared with the outputs of a selection, with paths relative to the target.
+pub struct Comparison {
+    /// Every file on the target, audio or not.
+    pub target_files: HashSet<PathBuf>,
+    /// Outputs of the selected tracks that are on the target.
+    pub in_sync: HashSet<PathBuf>,
+    /// Audio files on the target that no selected track is written to, excluding the older outputs of missing tracks.
+    pub extraneous: Vec<PathBuf>,
+    albums: BTreeMap<PathBuf, AlbumStatus>,
+    /// Source relative paths of the tracks with outputs missing from the target.
+    missing_tracks: Vec<PathBuf>,
+}
+
+/// Compares the outputs the selected tracks would be written to with the files on the target.
+pub fn compare(selection: &Selection, split_cue: bool) -> Result<Comparison> {
+    let Selection {
+        fs,
+        source_dir,
+        target_dir,
+        policy,
+        tracks,
+        ..
+    } = selection;
+
+    let target_files = fs
+        .exists(target_dir)?
+        .then(|| fs.build_file_list(target_dir))
+        .transpose()?
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|x| x.strip_prefix(target_dir).ok().map(Path::to_path_buf))
+        .collect::<HashSet<_>>();
+    let audio_files = target_files.iter().filter(|x| is_audio_file(x)).collect::<HashSet<_>>();
+
+    // Target files keyed by their path without an extension, to find tracks synced with another output format.
+    let mut target_stems = HashMap::<PathBuf, Vec<&PathBuf>>::new();
+    for file in &audio_files {
+        target_stems.entry(file.with_extension("")).or_default().push(file);
+    }
+
+    let mut albums = BTreeMap::<PathBuf, AlbumStatus>::new();
+    let mut accounted = HashSet::<&PathBuf>::new();
+    let mut in_sync = HashSet::new();
+    let mut missing_tracks = Vec::<PathBuf>::new();
+
+    for (file, meta) in tracks {
+        let rel_path = file
+            .strip_prefix(source_dir)
+            .map_err(|_| Error::descriptive("File path is outside of the source directory"))?;
+        let action = policy.action(rel_path, meta);
+        let plan = TrackPlan::new(file, rel_path, meta, action, split_cue)?;
+        let album = albums
+            .entry(rel_path.parent().map(Path::to_path_buf).unwrap_or_default())
+            .or_default();
+
+        for output in plan.outputs() {
+            if let Some(existing) = audio_files.get(&output.to_path_buf()) {
+                accounted.insert(existing);
+                in_sync.insert(output.to_path_buf());
+                album.in_sync += 1;
+                continue;
+            }
+
+            let stale = target_stems
+                .get(&output.with_extension(""))
+                .into_iter()
+                .flatten()
+                .filter(|x| !accounted.contains(*x))
+                .copied()
+                .collect::<Vec<_>>();
+            if !stale.is_empty() {
+                accounted.extend(stale);
+                album.outdated += 1;
+            } else {
+                album.missing += 1;
+            }
+
+            if missing_tracks.last().map(PathBuf::as_path) != Some(rel_path) {
+                missing_tracks.push(rel_path.to_path_buf());
+            }
+        }
+    }
+
+    let mut extraneous = audio_files
+        .iter()
+        .filter(|x| !accounted.contains(*x))
+        .map(|x| x.to_path_buf())
+        .collect::<Vec<_>>();
+    extraneous.sort();
+
+    for file in &extraneous {
+        let album = file.parent().map(Path::to_path_buf).unwrap_or_default();
+        albums.entry(album).or_default().extraneous += 1;
+    }
+
+    Ok(Comparison {
+        target_files,
+        in_sync,
+        extraneous,
+        albums,
+        missing_tracks,
+    })
+}
+
+/// Compares the tracks a sync would write with the files on the target, without changing anything.
+pub fn run(opts: StatusOpts, config: &Config) -> Result<()> {
+    let sync = opts.sync.resolve(config)?;
+    let Comparison {
+        albums, missing_tracks, ..
+    } = compare(&sync.select()?, sync.split_cue())?;
+
+    let mut total = AlbumStatus::default();
+    for status in albums.values() {
+        total.add(status);
+    }
+
+    println!("{:>12} {}", "In sync:", total.in_sync.to_string().green());
+    println!("{:>12} {}", "Missing:", total.missing.to_string().yellow());
+    println!("{:>12} {}", "Outdated:", total.outdated.to_string().yellow());
+    println!("{:>12} {}", "Extraneous:", total.extraneous.to_string().red());
+
+    let listed = albums
+        .iter()
+        .filter(|(_, x)| opts.all || !x.is_in_sync())
+        .collect::<Vec<_>>();
+    if !listed.is_empty() {
+        println!();
+        for (album, status) in listed {
+            let mut parts = vec![];
+            if status.in_sync > 0 {
+                parts.push(format!("{} in sync", status.in_sync).green());
+            }
+            if status.missing > 0 {
+                parts.push(format!("{} missing", status.missing).yellow());
+            }
+            if status.outdated > 0 {
+                parts.push(format!("{} outdated", status.outdated).yellow());
+            }
+            if status.extraneous > 0 {
+                parts.push(format!("{} extraneous", status.extraneous).red());
+            }
+
+            let parts = parts.iter().map(ToString::to_string).collect::<Vec<_>>();
+            println!("  {} {}", album.display(), parts.join(", "));
+        }
+    }
+
+    if let Some(path) = &opts.missing_list {
+        let contents = missing_tracks
+            .iter()
+            .map(|x| format!("{}\n", sync_list_entry(x)))
+            .collect::<String>();
+        fs::write(path, contents).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))?;
+
+        println!(
+            "\nWrote {} missing tracks to {}",
+            missing_tracks.len().to_string().yellow(),
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn is_audio_file(path: &Path) -> bool {
+    let Some(ext) = path.extension().and_then(|x| x.to_str()) else {
+        return false;
+    };
+
+    CONTAINERS
+        .iter()
+        .flat_map(|(_, extensions, _)| extensions.iter())
+        .any(|x| x.eq_ignore_ascii_case(ext))
+}
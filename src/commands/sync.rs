@@ -1,37 +1,86 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     env, fs, io,
     path::{Path, PathBuf},
     sync::{Arc, mpsc},
     thread,
+    time::{Duration, Instant},
 };
 
 use clap::Args;
 use colored::*;
 use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    budget::{self, Album, Priority, format_size},
+    config::Config,
+    cue::{CueSegment, CueSheet},
     errors::{Error, Result},
-    format::{Codec, get_track_data},
+    format::{
+        Codec, SourceFormat, TrackData,
+        encoder::{BitrateMode, Encoder, LosslessLimits, Resample},
+        get_track_data,
+    },
+    loudness::{self, Loudness, ReplayGainMode},
+    playlist::{self, Playlist, PlaylistEntry, PlaylistFormat, PlaylistMode},
+    query::{Query, select_tracks},
+    rules::{Policy, Rule, TrackAction, read_rules},
     utils::{
-        ffmpeg::transcode_file,
+        ffmpeg::{TranscodeSource, transcode_file},
         fs::{FSBackend, read_dir_recursively, read_selectively},
-        parse_sync_list,
-        path::PathExtensions,
+        ignore::Ignore,
+        parse_sync_list, parse_sync_playlist,
+        path::{PathExtensions, expand_home, normalize_path, relative_path},
     },
+    watch::LibraryWatcher,
 };
 
-#[derive(Debug, Args)]
+/// How often `--watch` checks for settled albums and whether the target became available.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+/// How long `--watch` waits before retrying a failed sync.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Options only `sync` itself takes, on top of the ones shared with the commands comparing a sync with the target.
+#[derive(Debug, Clone, Args)]
+pub struct SyncCommandOpts {
+    #[command(flatten)]
+    sync: SyncOpts,
+
+    #[arg(long)]
+    /// Keeps running after the sync, watching the source directory and syncing new or changed tracks once their
+    /// album folder stops changing. Waits for the target to become available, like a device being plugged in.
+    watch: bool,
+
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    /// How long an album folder has to stay unchanged before `--watch` syncs it.
+    settle: u64,
+}
+
+/// Options of a sync, either passed on the command line or read from a profile in the configuration file.
+///
+/// Every field is optional so that command line values can be layered over a profile, see [`SyncOpts::merge`].
+#[derive(Debug, Clone, Default, Args, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
 pub struct SyncOpts {
     /// The source directory to sync from.
-    source: PathBuf,
+    source: Option<PathBuf>,
 
     /// The directory to sync to.
-    target: PathBuf,
+    target: Option<PathBuf>,
 
-    #[arg(long, short, default_value = "adb")]
-    /// Specifies the filesystem backend to use for syncing.
-    fs: FSBackend,
+    #[arg(long, short)]
+    #[serde(skip)]
+    /// The configuration profile to read default values from.
+    profile: Option<String>,
+
+    #[arg(long, short)]
+    /// Specifies the filesystem backend to use for syncing. [default: adb]
+    fs: Option<FSBackend>,
+
+    #[arg(long, short)]
+    /// The serial of the ADB device to sync to, required when more than one device is connected.
+    serial: Option<String>,
 
     #[arg(long, short)]
     /// The codec to transcode into for tracks matching the transcode_codecs.
@@ -40,98 +89,725 @@ pub struct SyncOpts {
     codec: Option<Codec>,
 
     #[arg(long, short)]
-    /// The bitrate to use while transcoding files matched by `transcode_extensions`.
-    /// Only applies if `codec` is set.
+    /// The bitrate in kbps to use while transcoding files matched by `transcode_codecs`.
+    /// Only applies if `codec` is set, and cannot be used with flac or alac.
     ///
     /// Default values:
     /// - opus: 128K
-    /// - vorbis: 192K
-    /// - mp3: 320K
     /// - aac-lc: 192K
+    /// - vorbis and mp3 use `quality` unless a bitrate is set.
     bitrate: Option<u32>,
 
-    #[arg(long, default_value_t = false)]
+    #[arg(long, short, allow_negative_numbers = true)]
+    /// The quality level of the encoder, used instead of a bitrate.
+    ///
+    /// - vorbis: `-q` levels from -1 to 10. [default: 6]
+    /// - mp3: LAME `-V` levels from 0 (best) to 9, `0` and `2` matching the `extreme` and `standard` presets. [default: 2]
+    /// - flac: compression levels from 0 (fastest) to 12 (smallest). [default: 5]
+    quality: Option<f32>,
+
+    #[arg(long)]
+    /// How the bitrate is managed by opus (vbr, cvbr or cbr) and mp3 (vbr for an average bitrate, or cbr).
+    ///
+    /// Defaults to vbr for opus and cbr for mp3 when a bitrate is set.
+    bitrate_mode: Option<BitrateMode>,
+
+    #[arg(long)]
+    /// Caps the sample rate of lossless (flac, alac) outputs, e.g. `48000`.
+    ///
+    /// Lossless tracks that would be synced as is are transcoded into their own codec when they exceed the cap.
+    max_sample_rate: Option<u32>,
+
+    #[arg(long)]
+    /// Caps the bit depth of lossless outputs to either 16 or 24, dithering when reducing it.
+    ///
+    /// Lossless tracks that would be synced as is are transcoded into their own codec when they exceed the cap.
+    max_bit_depth: Option<u32>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    /// Splits album images with a CUE sheet next to them (`album.cue` or `album.flac.cue`) into a file per track,
+    /// named and tagged after the sheet's TITLE and PERFORMER entries.
+    ///
+    /// Images are split while transcoding, lossless images that would be synced as is are re-encoded into their own
+    /// codec.
+    split_cue: Option<bool>,
+
+    #[arg(long)]
+    /// Measures the loudness of transcoded tracks (EBU R128) and applies it per album.
+    ///
+    /// - tags: writes ReplayGain tags, or R128 tags for opus, leaving the audio untouched.
+    /// - bake: applies the album gain to the audio, limited to prevent clipping, for players that ignore tags.
+    replaygain: Option<ReplayGainMode>,
+
+    #[arg(long, value_name = "GB")]
+    /// Fits the sync into a size budget in gigabytes, e.g. `64`, leaving out whole albums ranked by `priority`.
+    ///
+    /// Sizes are estimated from the duration and bitrate of transcoded tracks, and the source size of everything else,
+    /// counting the tracks already on the target.
+    max_size: Option<f64>,
+
+    #[arg(long, value_delimiter = ',')]
+    /// A comma-separated list of what albums are ranked by when they don't fit into `max_size`, earlier ones taking
+    /// precedence. [default: sync-list,rating,play-count,recent]
+    priority: Option<Vec<Priority>>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    /// Lowers the bitrate of the transcode codec until everything fits into `max_size`, before leaving albums out.
+    fit_bitrate: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    /// Starts the sync even when the target doesn't seem to have enough free space, warning instead of refusing.
+    ignore_free_space: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
     /// When enabled, extras like covers are included with the sync.
-    include_extras: bool,
+    include_extras: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    /// When enabled, `.lrc` sidecars are synced next to their tracks, renamed to match the target file.
+    include_lyrics: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    /// Writes embedded lyrics (`LYRICS`/`USLT` tags) to a `.lrc` sidecar on the target when the track has none.
+    extract_lyrics: Option<bool>,
 
-    #[arg(long, value_delimiter = ',', default_value = "flac,alac")]
-    /// A comma-separated list of codecs to match to include in the transcode process.
-    transcode_codecs: Vec<Codec>,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    /// Embeds the contents of `.lrc` sidecars into transcoded tracks.
+    embed_lyrics: Option<bool>,
 
-    #[arg(long, value_delimiter = ',', default_value = "opus,vorbis,mp3,aac-lc")]
-    /// A comma-separated list of codecs to match to include only in the sync process.
-    sync_codecs: Vec<Codec>,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    /// When enabled, M3U/M3U8, PLS and XSPF playlists found in the source directory are rewritten to point at the
+    /// synced tracks and pushed to the target.
+    ///
+    /// Entries referencing tracks that are not part of the sync are dropped and reported.
+    include_playlists: Option<bool>,
+
+    #[arg(long)]
+    /// The folder on the target to push rewritten playlists into. Relative paths are resolved against the target.
+    ///
+    /// Playlists keep their location relative to the source directory if not set.
+    playlist_dir: Option<PathBuf>,
+
+    #[arg(long, value_enum)]
+    /// How playlists are synced with the target. [default: push]
+    ///
+    /// `two-way` keeps the last synced version of each playlist and merges the changes made in the library and on the
+    /// target since, translating the track paths between both. Changes it cannot merge are reported and keep the
+    /// entries of both sides.
+    playlist_mode: Option<PlaylistMode>,
+
+    #[arg(long, value_delimiter = ',')]
+    /// A comma-separated list of source formats to match to include in the transcode process. [default: flac,alac]
+    ///
+    /// Besides the output codecs, `pcm` (WAV/AIFF), `wav-pack`, `ape` and `dsd` can be transcoded from.
+    transcode_codecs: Option<Vec<SourceFormat>>,
+
+    #[arg(long, value_delimiter = ',')]
+    /// A comma-separated list of source formats to match to include only in the sync process.
+    /// [default: opus,vorbis,mp3,aac-lc]
+    sync_codecs: Option<Vec<SourceFormat>>,
 
     #[arg(
         long,
         long_help = "\
 A text file containing a list of folders to sync. Folders listed must be exist within the source directory.
+Lines can also be globs, exclusions starting with `!`, `%include <file>` to pull in another sync list, or tag
+queries starting with `?` that select matching albums from the whole source directory. Paths starting with one of
+these characters can be written as `./!Live`.
 
 E.g. source -> ~/Music/Library:
     ESAI
     ~/Music/Library/K03
-    ~/Music/Library/Various Artists/Stream Palette 4
-    Various Artists/Stream Palette 5 -RANKED-"
+    Various Artists/Stream Palette *
+    !Various Artists/Stream Palette 5 -RANKED-
+    %include jazz.txt
+    ? genre = Jazz AND year >= 1960"
     )]
     sync_list: Option<PathBuf>,
+
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// An M3U/M3U8, PLS or XSPF playlist whose tracks should be synced. Can be passed multiple times.
+    ///
+    /// Relative entries are resolved against the playlist's location, then against the source directory.
+    /// When used together with `sync_list`, the union of both is synced.
+    sync_playlist: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        long_help = "\
+A tag query narrowing down the tracks to sync, e.g. `genre = Jazz AND year >= 1960`.
+
+Comparisons are written as `<field> <op> <value>` with `=`, `!=`, `<`, `<=`, `>` and `>=`, and can be combined with
+AND, OR, NOT and parentheses. `=` is case-insensitive and accepts globs like `album = \"Stream Palette *\"`.
+
+Albums (tracks sharing a folder) are synced whole if any of their tracks match, unless `--partial-albums` is set."
+    )]
+    query: Option<String>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    /// Only syncs the tracks matching a query instead of their entire album.
+    partial_albums: Option<bool>,
+
+    #[arg(long, short = 'x')]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// A gitignore-style pattern relative to the source directory to exclude from the sync, e.g. `Bootlegs/` or
+    /// `*instrumental*`. Can be passed multiple times.
+    ///
+    /// `.tsyncignore` files anywhere in the source directory are honoured as well.
+    exclude: Vec<String>,
+
+    #[arg(long = "rules", value_name = "FILE")]
+    /// A TOML file of `[[rules]]` deciding per track whether to pass it through, transcode it with specific
+    /// settings or skip it. Tracks matching no rule fall back to `transcode_codecs` and `sync_codecs`.
+    ///
+    /// These are checked before the rules of the profile.
+    rules_file: Option<PathBuf>,
+
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<Rule>,
 }
 
-pub fn run(opts: SyncOpts) -> Result<()> {
-    let fs = opts.fs;
+impl SyncOpts {
+    /// Layers `self` over `base`, keeping the values set in `self`.
+    pub fn merge(self, base: SyncOpts) -> SyncOpts {
+        fn or_vec<T>(value: Vec<T>, base: Vec<T>) -> Vec<T> {
+            if value.is_empty() { base } else { value }
+        }
 
-    let source_dir = Path::new(&opts.source);
-    let target_dir = Path::new(&opts.target);
-    let temp_dir = env::temp_dir().join("tsync");
+        SyncOpts {
+            source: self.source.or(base.source),
+            target: self.target.or(base.target),
+            profile: self.profile.or(base.profile),
+            fs: self.fs.or(base.fs),
+            serial: self.serial.or(base.serial),
+            codec: self.codec.or(base.codec),
+            bitrate: self.bitrate.or(base.bitrate),
+            quality: self.quality.or(base.quality),
+            bitrate_mode: self.bitrate_mode.or(base.bitrate_mode),
+            max_sample_rate: self.max_sample_rate.or(base.max_sample_rate),
+            max_bit_depth: self.max_bit_depth.or(base.max_bit_depth),
+            split_cue: self.split_cue.or(base.split_cue),
+            replaygain: self.replaygain.or(base.replaygain),
+            max_size: self.max_size.or(base.max_size),
+            priority: self.priority.or(base.priority),
+            fit_bitrate: self.fit_bitrate.or(base.fit_bitrate),
+            ignore_free_space: self.ignore_free_space.or(base.ignore_free_space),
+            include_extras: self.include_extras.or(base.include_extras),
+            include_lyrics: self.include_lyrics.or(base.include_lyrics),
+            extract_lyrics: self.extract_lyrics.or(base.extract_lyrics),
+            embed_lyrics: self.embed_lyrics.or(base.embed_lyrics),
+            include_playlists: self.include_playlists.or(base.include_playlists),
+            playlist_dir: self.playlist_dir.or(base.playlist_dir),
+            playlist_mode: self.playlist_mode.or(base.playlist_mode),
+            transcode_codecs: self.transcode_codecs.or(base.transcode_codecs),
+            sync_codecs: self.sync_codecs.or(base.sync_codecs),
+            sync_list: self.sync_list.or(base.sync_list),
+            sync_playlist: or_vec(self.sync_playlist, base.sync_playlist),
+            query: self.query.or(base.query),
+            partial_albums: self.partial_albums.or(base.partial_albums),
+            exclude: or_vec(self.exclude, base.exclude),
+            rules_file: self.rules_file.or(base.rules_file),
+            rules: or_vec(self.rules, base.rules),
+        }
+    }
 
-    if !fs.available()? {
-        let message = format!("{fs:?} is not available! Make sure everything is right.");
-        return Err(Error::descriptive(message));
+    /// The values used when neither the command line nor the profile sets them.
+    pub fn defaults() -> SyncOpts {
+        SyncOpts {
+            fs: Some(FSBackend::Adb),
+            split_cue: Some(false),
+            priority: Some(Priority::defaults()),
+            fit_bitrate: Some(false),
+            ignore_free_space: Some(false),
+            include_extras: Some(false),
+            include_lyrics: Some(false),
+            extract_lyrics: Some(false),
+            embed_lyrics: Some(false),
+            include_playlists: Some(false),
+            playlist_mode: Some(PlaylistMode::Push),
+            transcode_codecs: Some(vec![SourceFormat::Flac, SourceFormat::Alac]),
+            sync_codecs: Some(vec![
+                SourceFormat::Opus,
+                SourceFormat::Vorbis,
+                SourceFormat::Mp3,
+                SourceFormat::AacLc,
+            ]),
+            partial_albums: Some(false),
+            ..Default::default()
+        }
     }
 
-    let sync_list_files = opts.sync_list.map(|x| parse_sync_list(source_dir, &x)).transpose()?;
+    /// Expands `~` in the paths referring to the local machine.
+    pub fn with_expanded_home(mut self) -> SyncOpts {
+        let expand = |path: PathBuf| expand_home(&path.to_string_lossy());
 
-    if let Err(e) = fs::create_dir(&temp_dir) {
-        if e.kind() != io::ErrorKind::AlreadyExists {
-            return Err(e.into());
+        self.source = self.source.map(expand);
+        self.sync_list = self.sync_list.map(expand);
+        self.sync_playlist = self.sync_playlist.into_iter().map(expand).collect();
+        self.rules_file = self.rules_file.map(expand);
+        self
+    }
+
+    /// Layers the command line options over the selected profile and the defaults.
+    ///
+    /// Rules read from `rules_file` are placed before the rules of the profile.
+    pub fn resolve(self, config: &Config) -> Result<SyncOpts> {
+        let profile = match &self.profile {
+            Some(name) => config.profile(name)?,
+            None => SyncOpts::default(),
+        };
+
+        let mut opts = self.merge(profile).merge(SyncOpts::defaults());
+        if let Some(path) = opts.rules_file.take() {
+            let mut rules = read_rules(&path)?;
+            rules.append(&mut opts.rules);
+            opts.rules = rules;
         }
 
-        fs::remove_dir_all(&temp_dir)?;
-        fs::create_dir(&temp_dir)?;
+        Ok(opts)
     }
+}
 
-    let bitrate = opts
-        .codec
-        .as_ref()
-        .map(|c| c.matching_bitrate(opts.bitrate))
-        .transpose()?;
-    if bitrate.is_some() && opts.transcode_codecs.iter().any(|tc| opts.sync_codecs.contains(tc)) {
-        return Err(Error::descriptive("Sync and transcode codecs cannot overlap!"));
+/// Reads the track data of every file, showing progress as large libraries can take a while.
+///
+/// Files that can't be probed, like videos or corrupt files sharing an audio extension, are reported and left out.
+fn probe_tracks(files: Vec<PathBuf>) -> Result<Vec<(PathBuf, TrackData)>> {
+    let indicator = {
+        let style = ProgressStyle::with_template("{msg}\n[{elapsed_precise}] [{wide_bar:.cyan/blue}] [{pos}/{len}]")
+            .unwrap_or_else(|_| ProgressStyle::default_bar())
+            .progress_chars("#>-");
+        ProgressBar::new(files.len() as u64).with_style(style)
+    };
+
+    let mut tracks = Vec::with_capacity(files.len());
+    for file in files {
+        indicator.set_message(format!("Reading {}", file.get_file_name()));
+
+        let extension = file
+            .get_file_ext()
+            .ok_or_else(|| Error::descriptive("Track file has no extension").with_context(file.to_string_lossy()))?;
+        match get_track_data(&file, &extension) {
+            Ok(meta) => tracks.push((file, meta)),
+            Err(e) => indicator.suspend(|| println!("{} {e}", "Skipping unreadable track:".yellow())),
+        }
+
+        indicator.inc(1);
     }
 
-    let files = {
-        let readable_extensions = if opts.codec.is_some() {
-            opts.transcode_codecs
-                .iter()
-                .chain(opts.sync_codecs.iter())
-                .map(|x| x.extenstion_str())
-                .collect::<Vec<&'static str>>()
-        } else {
-            opts.sync_codecs
+    indicator.finish_and_clear();
+    Ok(tracks)
+}
+
+/// The backend and locations shared by the stages of a sync.
+struct SyncEnv<'a> {
+    fs: &'a FSBackend,
+    source_dir: &'a Path,
+    target_dir: &'a Path,
+    temp_dir: &'a Path,
+    ignore: &'a Ignore,
+}
+
+#[derive(Debug, Clone)]
+struct TranscodeJob {
+    file: PathBuf,
+    format: SourceFormat,
+    /// The audio stream to transcode, counted among the audio streams of the file.
+    audio_track: usize,
+    /// The start and length of a track cut out of an album image.
+    range: Option<(f64, Option<f64>)>,
+    target_rel: PathBuf,
+    rel_path: PathBuf,
+    /// Tags written on top of the ones carried over, like embedded lyrics.
+    metadata: Vec<(&'static str, String)>,
+    encoder: Encoder,
+    resample: Option<Resample>,
+    /// The volume change in dB baked into the audio.
+    volume: Option<f64>,
+    /// Index of the job's source within the loudness units of the sync.
+    loudness_unit: usize,
+}
+
+/// The audio of a transcoded track, measured for its loudness together with the rest of its album.
+#[derive(Debug, Clone)]
+struct LoudnessUnit {
+    /// The source folder, standing for the album.
+    album: PathBuf,
+    file: PathBuf,
+    audio_track: usize,
+    range: Option<(f64, Option<f64>)>,
+}
+
+enum LyricsSource {
+    /// A `.lrc` file next to the source track.
+    Sidecar(PathBuf),
+    /// Lyrics read from the source track's tags.
+    Embedded(String),
+}
+
+/// What a sync selects from the source directory, shared by the commands comparing the library with the target.
+pub struct Selection {
+    pub fs: FSBackend,
+    pub source_dir: PathBuf,
+    pub target_dir: PathBuf,
+    pub ignore: Ignore,
+    pub policy: Policy,
+    /// The selected tracks, fitted into `max_size` when set.
+    pub tracks: Vec<(PathBuf, TrackData)>,
+}
+
+/// How a track is written to the target, with paths relative to the target directory.
+#[derive(Debug, Clone)]
+pub enum TrackPlan {
+    /// An album image cut into a file per CUE sheet track, each one transcoded with the encoder.
+    Split(Encoder, Vec<(PathBuf, CueSegment)>),
+    Transcode(Encoder, PathBuf),
+    Passthrough(PathBuf),
+    Skip,
+}
+
+impl TrackPlan {
+    /// Plans `file` for the `action` decided by the policy, splitting it along its CUE sheet when `split_cue` is set.
+    pub fn new(
+        file: &Path,
+        rel_path: &Path,
+        meta: &TrackData,
+        action: TrackAction,
+        split_cue: bool,
+    ) -> Result<TrackPlan> {
+        let cue = match action {
+            TrackAction::Transcode(_) | TrackAction::Passthrough if split_cue => CueSheet::read_for(file)?,
+            _ => None,
+        };
+        // Lossless images that would be synced as is are re-encoded into their own codec to split them.
+        let split_encoder = match action {
+            TrackAction::Transcode(encoder) => Some(encoder),
+            _ => meta
+                .format
+                .codec()
+                .filter(Codec::is_lossless)
+                .and_then(|x| Encoder::new(x, None, None, None).ok()),
+        };
+
+        if let Some((sheet, cue_file)) = cue
+            && let Some(encoder) = split_encoder
+        {
+            let (_, new_ext) = encoder.codec().output_container();
+            let parent_rel = rel_path.parent().map(Path::to_path_buf).unwrap_or_default();
+            let segments = sheet
+                .segments(&cue_file)
+                .into_iter()
+                .map(|x| (parent_rel.join(format!("{}.{new_ext}", x.file_stem)), x))
+                .collect();
+
+            return Ok(TrackPlan::Split(encoder, segments));
+        }
+
+        let plan = match action {
+            TrackAction::Transcode(encoder) => {
+                let (_, new_ext) = encoder.codec().output_container();
+                TrackPlan::Transcode(encoder, rel_path.with_extension(new_ext))
+            }
+            TrackAction::Passthrough => TrackPlan::Passthrough(rel_path.to_path_buf()),
+            TrackAction::Skip => TrackPlan::Skip,
+        };
+
+        Ok(plan)
+    }
+
+    /// The files the track is written to.
+    pub fn outputs(&self) -> Vec<&Path> {
+        match self {
+            TrackPlan::Split(_, segments) => segments.iter().map(|(x, _)| x.as_path()).collect(),
+            TrackPlan::Transcode(_, target_rel) | TrackPlan::Passthrough(target_rel) => vec![target_rel],
+            TrackPlan::Skip => vec![],
+        }
+    }
+}
+
+impl SyncOpts {
+    /// Reads and probes the tracks selected by the resolved options, fitting them into `max_size` when set.
+    ///
+    /// Fails when the backend isn't available, as every command built on a selection compares it with the target.
+    pub fn select(&self) -> Result<Selection> {
+        self.select_within(None)
+    }
+
+    /// Like [`SyncOpts::select`], but only reads the files within `dirs` when given, so that watching a large library
+    /// doesn't probe every track for each changed album.
+    fn select_within(&self, dirs: Option<&[PathBuf]>) -> Result<Selection> {
+        let fs = self.fs.clone().unwrap_or_default();
+        let partial_albums = self.partial_albums.unwrap_or_default();
+
+        let source_dir = self.source.as_deref().ok_or_else(|| {
+            Error::descriptive("No source directory was given, either as an argument or in the profile")
+        })?;
+        let target_dir = self.target.as_deref().ok_or_else(|| {
+            Error::descriptive("No target directory was given, either as an argument or in the profile")
+        })?;
+
+        if let Some(serial) = &self.serial {
+            FSBackend::select_device(serial);
+        }
+
+        if !fs.available()? {
+            let message = format!("{fs:?} is not available! Make sure everything is right.");
+            return Err(Error::descriptive(message));
+        }
+
+        let ignore = Ignore::new(source_dir, &self.exclude)?;
+        let mut sync_list = self
+            .sync_list
+            .as_deref()
+            .map(|x| parse_sync_list(source_dir, x))
+            .transpose()?;
+        let query = self.query.as_deref().map(str::parse::<Query>).transpose()?;
+
+        for playlist in &self.sync_playlist {
+            let (tracks, missing) = parse_sync_playlist(source_dir, playlist)?;
+
+            if !missing.is_empty() {
+                let message = format!(
+                    "{} has {} entries missing from the source directory:",
+                    playlist.display(),
+                    missing.len().to_string().yellow()
+                );
+                println!("{message}");
+                for location in missing {
+                    println!("  {}", location.dimmed());
+                }
+            }
+
+            let list = sync_list.get_or_insert_default();
+            for track in tracks {
+                list.add_path(track);
+            }
+        }
+
+        let mut policy = self.policy()?;
+
+        let tracks = {
+            let mut readable_extensions = policy
+                .readable_formats()
                 .iter()
-                .map(|x| x.extenstion_str())
-                .collect::<Vec<&'static str>>()
+                .flat_map(|x| x.extensions())
+                .collect::<Vec<&'static str>>();
+            readable_extensions.sort_unstable();
+            readable_extensions.dedup();
+
+            let readable_extensions = Some(readable_extensions);
+            let is_excluded = |file: &PathBuf| {
+                let rel_path = file.strip_prefix(source_dir).unwrap_or(file);
+                sync_list.as_ref().is_some_and(|list| list.is_excluded(rel_path))
+            };
+
+            let read_source = || match dirs {
+                Some(dirs) => read_selectively(dirs, &readable_extensions, &ignore),
+                None => read_dir_recursively(source_dir, &readable_extensions, &ignore),
+            };
+
+            let mut tracks = match &sync_list {
+                // Queries within a sync list select from the whole library, so everything has to be read.
+                Some(list) if !list.queries.is_empty() => {
+                    let mut files = read_source()?;
+                    files.retain(|x| !is_excluded(x));
+
+                    let tracks = probe_tracks(files)?;
+                    let mut selected = tracks
+                        .iter()
+                        .filter(|(file, _)| list.paths.iter().any(|x| file.starts_with(x)))
+                        .map(|(file, _)| file.clone())
+                        .collect::<HashSet<_>>();
+
+                    for query in &list.queries {
+                        let iter = tracks.iter().map(|(file, track)| (file.as_path(), track));
+                        selected.extend(select_tracks(iter, query, partial_albums));
+                    }
+
+                    tracks.into_iter().filter(|(file, _)| selected.contains(file)).collect()
+                }
+                Some(list) if !list.selects_everything() => {
+                    let mut files = match dirs {
+                        Some(dirs) => {
+                            let mut files = read_selectively(dirs, &readable_extensions, &ignore)?;
+                            files.retain(|x| list.position(x).is_some());
+                            files
+                        }
+                        None => read_selectively(&list.paths, &readable_extensions, &ignore)?,
+                    };
+                    // Overlapping entries like `Artist` and `Artist/Album` would otherwise yield the same file twice.
+                    let mut seen = HashSet::new();
+                    files.retain(|x| !is_excluded(x) && seen.insert(x.clone()));
+
+                    probe_tracks(files)?
+                }
+                _ => {
+                    let mut files = read_source()?;
+                    files.retain(|x| !is_excluded(x));
+
+                    probe_tracks(files)?
+                }
+            };
+
+            if let Some(query) = &query {
+                let iter = tracks.iter().map(|(file, track)| (file.as_path(), track));
+                let selected = select_tracks(iter, query, partial_albums);
+                tracks.retain(|(file, _)| selected.contains(file));
+            }
+
+            tracks
         };
 
-        if let Some(within) = &sync_list_files {
-            read_selectively(within, &Some(readable_extensions))?
-        } else {
-            read_dir_recursively(source_dir, &Some(readable_extensions))?
+        println!("Found {} files", tracks.len().to_string().green());
+
+        let tracks = match self.max_size {
+            Some(max_size) => {
+                let max_size = (max_size * 1e9) as u64;
+                let mut albums = Album::group(tracks, sync_list.as_ref())?;
+                budget::rank(&mut albums, self.priority.as_deref().unwrap_or_default());
+
+                if self.fit_bitrate.unwrap_or_default() {
+                    let encoder = *policy
+                        .transcode()
+                        .ok_or_else(|| Error::descriptive("Fitting the bitrate requires a transcode codec"))?;
+                    policy =
+                        budget::fit_bitrate(&encoder, &albums, source_dir, max_size, |x| self.build_policy(Some(x)))?;
+
+                    if let Some(bitrate) = policy.transcode().and_then(|x| x.nominal_bitrate())
+                        && Some(bitrate) != encoder.nominal_bitrate()
+                    {
+                        println!(
+                            "Lowered the bitrate to {}K to fit the size budget",
+                            bitrate.to_string().yellow()
+                        );
+                    }
+                }
+
+                let (kept, left_out) = budget::fit(albums, source_dir, &policy, max_size);
+                let used = kept.iter().map(|x| x.size(source_dir, &policy)).sum::<u64>();
+                println!(
+                    "Estimated size: {} of {}",
+                    format_size(used).green(),
+                    format_size(max_size)
+                );
+
+                if !left_out.is_empty() {
+                    let size = left_out.iter().map(|(_, size)| size).sum::<u64>();
+                    println!(
+                        "Left out {} albums ({}) that don't fit into the size budget:",
+                        left_out.len().to_string().yellow(),
+                        format_size(size)
+                    );
+                    for (album, size) in &left_out {
+                        let dir = album.dir.strip_prefix(source_dir).unwrap_or(&album.dir);
+                        println!(
+                            "  {} {}",
+                            dir.display().to_string().dimmed(),
+                            format_size(*size).dimmed()
+                        );
+                    }
+                }
+
+                kept.into_iter().flat_map(Album::into_tracks).collect()
+            }
+            None => tracks,
+        };
+
+        Ok(Selection {
+            fs,
+            source_dir: source_dir.to_path_buf(),
+            target_dir: target_dir.to_path_buf(),
+            ignore,
+            policy,
+            tracks,
+        })
+    }
+
+    /// The policy deciding what happens to each track, transcoding with the codec and bitrate of the options.
+    pub fn policy(&self) -> Result<Policy> {
+        let transcode = self
+            .codec
+            .map(|c| Encoder::new(c, self.bitrate, self.quality, self.bitrate_mode))
+            .transpose()?;
+
+        self.build_policy(transcode)
+    }
+
+    fn build_policy(&self, transcode: Option<Encoder>) -> Result<Policy> {
+        let transcode_codecs = self.transcode_codecs.clone().unwrap_or_default();
+        let sync_codecs = self.sync_codecs.clone().unwrap_or_default();
+        if transcode.is_some() && transcode_codecs.iter().any(|tc| sync_codecs.contains(tc)) {
+            return Err(Error::descriptive("Sync and transcode codecs cannot overlap!"));
         }
-    };
 
-    println!("Found {} files", files.len().to_string().green());
+        let limits = LosslessLimits::new(self.max_sample_rate, self.max_bit_depth)?;
+        Policy::new(self.rules.clone(), transcode, limits, transcode_codecs, sync_codecs)
+    }
+
+    /// Whether album images are split along their CUE sheets, see [`TrackPlan::new`].
+    pub fn split_cue(&self) -> bool {
+        self.split_cue.unwrap_or_default()
+    }
+
+    pub fn fs(&self) -> FSBackend {
+        self.fs.clone().unwrap_or_default()
+    }
+
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    pub fn source(&self) -> Option<&Path> {
+        self.source.as_deref()
+    }
+
+    pub fn target(&self) -> Option<&Path> {
+        self.target.as_deref()
+    }
+
+    pub fn codec(&self) -> Option<Codec> {
+        self.codec
+    }
+}
 
-    let track_count = files.len();
+/// The local directory transcodes and rewritten files are staged in before being copied to the target.
+pub fn temp_dir() -> PathBuf {
+    env::temp_dir().join("tsync")
+}
+
+pub fn run(opts: SyncCommandOpts, config: &Config) -> Result<()> {
+    let sync_opts = opts.sync.resolve(config)?;
+    if opts.watch {
+        return watch(&sync_opts, Duration::from_secs(opts.settle));
+    }
+
+    sync(&sync_opts, sync_opts.select()?, &HashSet::new())
+}
+
+/// Syncs the selected tracks, replacing the outputs of the files in `replace` when they are already on the target.
+fn sync(opts: &SyncOpts, selection: Selection, replace: &HashSet<PathBuf>) -> Result<()> {
+    let Selection {
+        fs,
+        source_dir,
+        target_dir,
+        ignore,
+        policy,
+        tracks,
+    } = selection;
+    let (source_dir, target_dir) = (source_dir.as_path(), target_dir.as_path());
+    let temp_dir = temp_dir();
+
+    if let Err(e) = fs::create_dir(&temp_dir) {
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(e.into());
+        }
+
+        fs::remove_dir_all(&temp_dir)?;
+        fs::create_dir(&temp_dir)?;
+    }
+
+    let track_count = tracks.len();
     let indicator = {
         let len = track_count as u64;
         let style = ProgressStyle::with_template("{msg}\n[{elapsed_precise}] [{wide_bar:.cyan/blue}] [{pos}/{len}]")
@@ -154,7 +830,7 @@ pub fn run(opts: SyncOpts) -> Result<()> {
         indicator.inc(1);
     };
 
-    let mut parent_set = HashSet::<PathBuf>::with_capacity(files.len() / 3);
+    let mut parent_set = HashSet::<PathBuf>::with_capacity(tracks.len() / 3);
     let target_file_list = fs
         .exists(target_dir)?
         .then(|| fs.build_file_list(target_dir))
@@ -163,60 +839,210 @@ pub fn run(opts: SyncOpts) -> Result<()> {
 
     let mut transcode_jobs = Vec::new();
     let mut sync_jobs = Vec::new();
+    let mut lyric_jobs = Vec::new();
+    let mut loudness_units = Vec::new();
+    // The estimated size of everything left to copy to the target.
+    let mut planned_size = 0;
+    // Every track selected for the target, including the ones already present, keyed by the source relative path.
+    let mut planned = HashMap::<PathBuf, PathBuf>::new();
 
-    for file in files {
+    for (file, meta) in tracks {
         let rel_path = file
             .strip_prefix(source_dir)
             .map_err(|_| Error::descriptive("File path is outside of the source directory"))?
             .to_path_buf();
-        let extension = file
-            .get_file_ext()
-            .ok_or_else(|| Error::descriptive("Track file has no extension").with_context(file.to_string_lossy()))?;
-        let meta = get_track_data(&file, &extension)?;
-        let is_syncable = opts.sync_codecs.contains(&meta.codec);
-        let is_transcodable = !is_syncable && opts.transcode_codecs.contains(&meta.codec);
+        let action = policy.action(&rel_path, &meta);
+        let source_size = || {
+            fs::metadata(&file)
+                .map(|x| x.len())
+                .map_err(|e| Error::from(e).with_context(file.to_string_lossy()))
+        };
 
         if let Some(x) = file.parent()
-            && (is_syncable || is_transcodable)
+            && action != TrackAction::Skip
         {
             parent_set.insert(x.to_path_buf());
         }
 
-        if is_transcodable && let Some(codec) = opts.codec {
-            let new_ext = codec.extenstion_str();
-            let target_rel = rel_path.with_extension(new_ext);
-            let target_path = target_dir.join(&target_rel);
+        let sidecar = file.get_lyrics_sidecar();
+        let on_target = |target_path: &Path| !replace.contains(&file) && target_file_list.contains(target_path);
+        let mut plan_lyrics = |target_rel: &Path, sidecar: Option<&PathBuf>, embedded: Option<&String>| {
+            let lyrics_rel = target_rel.with_extension("lrc");
+            let replaced = sidecar.is_some_and(|x| replace.contains(x));
+            if on_target(&target_dir.join(&lyrics_rel)) && !replaced {
+                return;
+            }
 
-            if target_file_list.contains(&target_path) {
-                path_already_exists(&target_rel, &indicator);
-                continue;
+            if let Some(sidecar) = sidecar
+                && opts.include_lyrics.unwrap_or_default()
+            {
+                lyric_jobs.push((LyricsSource::Sidecar(sidecar.clone()), lyrics_rel));
+            } else if sidecar.is_none()
+                && opts.extract_lyrics.unwrap_or_default()
+                && let Some(lyrics) = embedded
+            {
+                lyric_jobs.push((LyricsSource::Embedded(lyrics.clone()), lyrics_rel));
             }
+        };
 
-            transcode_jobs.push((file, target_rel, rel_path));
-        } else if is_syncable {
-            let target_path = target_dir.join(&rel_path);
+        match TrackPlan::new(&file, &rel_path, &meta, action, opts.split_cue())? {
+            TrackPlan::Split(encoder, segments) => {
+                indicator.inc_length(segments.len().saturating_sub(1) as u64);
 
-            if target_file_list.contains(&target_path) {
-                path_already_exists(&rel_path, &indicator);
-                continue;
+                // Playlist entries pointing at the image lead to its first track.
+                if let Some((target_rel, _)) = segments.first() {
+                    planned.insert(rel_path.clone(), target_rel.clone());
+                }
+
+                let pending_jobs = transcode_jobs.len();
+                for (target_rel, segment) in segments {
+                    let range = Some((segment.start, segment.duration));
+                    // Lyrics of the image as a whole don't fit any single track, only per-track sidecars like
+                    // `01 Title.lrc` next to the image are used.
+                    let segment_sidecar = file.with_file_name(target_rel.get_file_name()).get_lyrics_sidecar();
+                    plan_lyrics(&target_rel, segment_sidecar.as_ref(), None);
+
+                    loudness_units.push(LoudnessUnit {
+                        album: file.parent().map(Path::to_path_buf).unwrap_or_default(),
+                        file: file.clone(),
+                        audio_track: meta.audio_track,
+                        range,
+                    });
+
+                    if on_target(&target_dir.join(&target_rel)) {
+                        path_already_exists(&target_rel, &indicator);
+                        continue;
+                    }
+
+                    let mut metadata = segment.tags;
+                    if let Some(sidecar) = &segment_sidecar
+                        && opts.embed_lyrics.unwrap_or_default()
+                    {
+                        let lyrics = fs::read_to_string(sidecar)
+                            .map_err(|e| Error::from(e).with_context(sidecar.to_string_lossy()))?;
+                        metadata.push(("lyrics", lyrics));
+                    }
+
+                    transcode_jobs.push(TranscodeJob {
+                        file: file.clone(),
+                        format: meta.format,
+                        audio_track: meta.audio_track,
+                        range,
+                        target_rel,
+                        rel_path: rel_path.clone(),
+                        metadata,
+                        encoder,
+                        resample: policy.resample(&encoder, &meta),
+                        volume: None,
+                        loudness_unit: loudness_units.len() - 1,
+                    });
+                }
+
+                if transcode_jobs.len() > pending_jobs {
+                    planned_size += budget::estimate_size(&action, &policy, &meta, source_size()?);
+                }
             }
+            TrackPlan::Transcode(encoder, target_rel) => {
+                let target_path = target_dir.join(&target_rel);
 
-            sync_jobs.push((file, rel_path));
-        } else {
-            skipping(&rel_path, &indicator, Some("due to no codec"));
+                plan_lyrics(&target_rel, sidecar.as_ref(), meta.lyrics.as_ref());
+                planned.insert(rel_path.clone(), target_rel.clone());
+                loudness_units.push(LoudnessUnit {
+                    album: file.parent().map(Path::to_path_buf).unwrap_or_default(),
+                    file: file.clone(),
+                    audio_track: meta.audio_track,
+                    range: None,
+                });
+
+                if on_target(&target_path) {
+                    path_already_exists(&target_rel, &indicator);
+                    continue;
+                }
+
+                let mut metadata = vec![];
+                if let Some(sidecar) = &sidecar
+                    && opts.embed_lyrics.unwrap_or_default()
+                {
+                    let lyrics = fs::read_to_string(sidecar)
+                        .map_err(|e| Error::from(e).with_context(sidecar.to_string_lossy()))?;
+                    metadata.push(("lyrics", lyrics));
+                }
+
+                planned_size += budget::estimate_size(&action, &policy, &meta, source_size()?);
+
+                transcode_jobs.push(TranscodeJob {
+                    file,
+                    format: meta.format,
+                    audio_track: meta.audio_track,
+                    range: None,
+                    target_rel,
+                    rel_path,
+                    metadata,
+                    encoder,
+                    resample: policy.resample(&encoder, &meta),
+                    volume: None,
+                    loudness_unit: loudness_units.len() - 1,
+                });
+            }
+            TrackPlan::Passthrough(target_rel) => {
+                let target_path = target_dir.join(&target_rel);
+
+                plan_lyrics(&target_rel, sidecar.as_ref(), meta.lyrics.as_ref());
+                planned.insert(rel_path.clone(), target_rel.clone());
+
+                if on_target(&target_path) {
+                    path_already_exists(&target_rel, &indicator);
+                    continue;
+                }
+
+                planned_size += source_size()?;
+                sync_jobs.push((file, target_rel));
+            }
+            TrackPlan::Skip => skipping(&rel_path, &indicator, Some("due to no codec or rule")),
         }
     }
 
+    if planned_size > 0 {
+        let free_space = fs.free_space(target_dir).unwrap_or_else(|e| {
+            let message = format!("{} {e}", "Couldn't check the free space of the target:".yellow());
+            indicator.suspend(|| println!("{message}"));
+            None
+        });
+
+        if let Some(free_space) = free_space
+            && planned_size > free_space
+        {
+            let message = format!(
+                "The sync needs about {} but the target only has {} free",
+                format_size(planned_size),
+                format_size(free_space)
+            );
+            if !opts.ignore_free_space.unwrap_or_default() {
+                indicator.finish_and_clear();
+                return Err(Error::descriptive(format!(
+                    "{message}, pass --ignore-free-space to sync anyway"
+                )));
+            }
+
+            indicator.suspend(|| println!("{} {message}", "Warning:".yellow()));
+        }
+    }
+
+    // The size of everything copied to the target, for the summary.
+    let mut synced_size = 0;
+
+    if let Some(mode) = opts.replaygain
+        && !transcode_jobs.is_empty()
+    {
+        apply_replaygain(mode, &loudness_units, &mut transcode_jobs, &indicator)?;
+    }
+
     if !transcode_jobs.is_empty() {
         let num_threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
         let (tx, rx) = mpsc::channel();
         let mut handles = Vec::new();
 
-        let codec = opts
-            .codec
-            .ok_or_else(|| Error::descriptive("Codec must be set for transcode jobs"))?;
-        let bitrate = bitrate.ok_or_else(|| Error::descriptive("Bitrate must be set for transcode jobs"))?;
-        let temp_dir = Arc::new(temp_dir);
+        let temp_dir = Arc::new(temp_dir.clone());
 
         for chunk in transcode_jobs.chunks((transcode_jobs.len() / num_threads).max(1)) {
             let tx = tx.clone();
@@ -224,15 +1050,35 @@ pub fn run(opts: SyncOpts) -> Result<()> {
             let temp_dir = Arc::clone(&temp_dir);
 
             let handle = thread::spawn(move || {
-                for (file, target_rel, rel_path) in chunk {
+                for job in chunk {
+                    let TranscodeJob {
+                        file,
+                        format,
+                        audio_track,
+                        range,
+                        target_rel,
+                        rel_path,
+                        metadata,
+                        encoder,
+                        resample,
+                        volume,
+                        loudness_unit: _,
+                    } = job;
                     let temp_path = temp_dir.join(&target_rel);
 
                     if let Some(parent) = temp_path.parent() {
                         let _ = fs::create_dir_all(parent);
                     }
 
-                    let result =
-                        transcode_file(&file, &temp_path, codec, bitrate).map(|_| (temp_path, target_rel, rel_path));
+                    let source = TranscodeSource {
+                        path: &file,
+                        format,
+                        audio_track,
+                        range,
+                    };
+                    let metadata = metadata.iter().map(|(k, v)| (*k, v.as_str())).collect::<Vec<_>>();
+                    let result = transcode_file(&source, &temp_path, &encoder, resample.as_ref(), volume, &metadata)
+                        .map(|_| (temp_path, target_rel, rel_path));
 
                     let _ = tx.send(result);
                 }
@@ -251,6 +1097,7 @@ pub fn run(opts: SyncOpts) -> Result<()> {
 
             let target_path = target_dir.join(&target_rel);
             indicator.set_message(format!("Syncing {:?}", target_rel.get_file_name()));
+            synced_size += fs::metadata(&temp_path).map(|x| x.len()).unwrap_or_default();
 
             if let Err(e) = fs.cp(&temp_path, &target_path) {
                 let context = format!("While copying {temp_path:#?} to {target_path:#?}");
@@ -277,15 +1124,47 @@ pub fn run(opts: SyncOpts) -> Result<()> {
             return Err(e.with_context(context));
         }
 
+        synced_size += fs::metadata(&file).map(|x| x.len()).unwrap_or_default();
         indicator.inc(1);
     }
 
-    if opts.include_extras {
+    if !lyric_jobs.is_empty() {
+        indicator.inc_length(lyric_jobs.len() as u64);
+
+        for (source, lyrics_rel) in lyric_jobs {
+            let target_path = target_dir.join(&lyrics_rel);
+            indicator.set_message(format!("Syncing lyrics {}", lyrics_rel.get_file_name()));
+
+            let result = match source {
+                LyricsSource::Sidecar(file) => fs.cp(&file, &target_path),
+                LyricsSource::Embedded(lyrics) => {
+                    let temp_path = temp_dir.join(&lyrics_rel);
+                    if let Some(parent) = temp_path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+
+                    fs::write(&temp_path, lyrics)?;
+                    let result = fs.cp(&temp_path, &target_path);
+                    fs::remove_file(temp_path)?;
+                    result
+                }
+            };
+
+            if let Err(e) = result {
+                let context = format!("While copying lyrics to {target_path:#?}");
+                return Err(e.with_context(context));
+            }
+
+            indicator.inc(1);
+        }
+    }
+
+    if opts.include_extras.unwrap_or_default() {
         let exts = vec!["jpg", "png", "jpeg"];
-        let files = read_selectively(parent_set, &Some(exts))?;
+        let files = read_selectively(parent_set, &Some(exts), &ignore)?;
         let files = files.into_iter().filter(|x| x.is_extra()).collect::<Vec<_>>();
 
-        indicator.set_length((track_count + files.len()) as u64);
+        indicator.inc_length(files.len() as u64);
 
         for file in files {
             let rel_path = file
@@ -305,7 +1184,614 @@ pub fn run(opts: SyncOpts) -> Result<()> {
         }
     }
 
+    if opts.include_playlists.unwrap_or_default() {
+        let playlist_dir = opts.playlist_dir.as_ref().map(|x| target_dir.join(x));
+        let env = SyncEnv {
+            fs: &fs,
+            source_dir,
+            target_dir,
+            temp_dir: &temp_dir,
+            ignore: &ignore,
+        };
+        let mode = opts.playlist_mode.unwrap_or_default();
+        sync_playlists(&env, playlist_dir.as_deref(), mode, &planned, &indicator)?;
+    }
+
     indicator.finish_with_message("Done!");
 
+    let free_space = fs.free_space(target_dir).ok().flatten();
+    println!(
+        "Synced {}{}",
+        format_size(synced_size).green(),
+        free_space
+            .map(|x| format!(", {} free on the target", format_size(x)))
+            .unwrap_or_default()
+    );
+
+    Ok(())
+}
+
+/// Syncs everything once the target is available, then keeps watching the source directory and syncs the album
+/// folders that changed as soon as they settle.
+///
+/// Later passes only read the albums that changed, with sync lists and queries applying as usual. The size budget and
+/// playlists depend on the whole library, so passes go through the whole selection when either is used, holding back
+/// the albums still changing. Tracks already on the target are only replaced when their source file changed.
+fn watch(opts: &SyncOpts, settle: Duration) -> Result<()> {
+    let source_dir = opts
+        .source
+        .as_deref()
+        .ok_or_else(|| Error::descriptive("No source directory was given, either as an argument or in the profile"))?;
+    let target_dir = opts
+        .target
+        .as_deref()
+        .ok_or_else(|| Error::descriptive("No target directory was given, either as an argument or in the profile"))?;
+    let fs = opts.fs();
+
+    if let Some(serial) = &opts.serial {
+        FSBackend::select_device(serial);
+    }
+
+    let mut watcher = LibraryWatcher::new(source_dir)?;
+    println!(
+        "Watching {} for changes, press Ctrl+C to stop",
+        source_dir.display().to_string().cyan()
+    );
+
+    // The first pass catches up on everything changed while tsync wasn't running.
+    let mut initial = true;
+    let mut waiting = false;
+    let mut retry_at = None;
+    loop {
+        watcher.wait(WATCH_INTERVAL)?;
+
+        let now = Instant::now();
+        if retry_at.is_some_and(|x| now < x) {
+            continue;
+        }
+
+        let albums = watcher.changes.settled(settle, now);
+        if albums.is_empty() && !initial {
+            continue;
+        }
+
+        // A device or drive being plugged in makes the parent of the target show up. adb failing, like while the device
+        // is still booting, counts as the target not being there yet.
+        let available = fs.available().unwrap_or_default()
+            && target_dir
+                .parent()
+                .filter(|x| !x.as_os_str().is_empty())
+                .is_none_or(|x| fs.exists(x).unwrap_or_default());
+        if !available {
+            if !waiting {
+                println!("Waiting for the target to become available...");
+                waiting = true;
+            }
+            continue;
+        }
+        waiting = false;
+
+        if !initial {
+            println!("Syncing {} changed albums:", albums.len().to_string().green());
+            for album in &albums {
+                let dir = album.strip_prefix(source_dir).unwrap_or(album);
+                println!("  {}", dir.display().to_string().dimmed());
+            }
+        }
+
+        let pending = watcher.changes.pending(settle, now);
+        let replace = watcher.changes.files_in(&albums);
+        // Removed albums have nothing left to read, and folders within another changed one are read along with it.
+        let dirs = albums
+            .iter()
+            .filter(|x| x.is_dir() && !albums.iter().any(|y| y != *x && x.starts_with(y)))
+            .cloned()
+            .collect::<Vec<_>>();
+        let whole_library = initial || opts.max_size.is_some() || opts.include_playlists.unwrap_or_default();
+        let result = opts
+            .select_within((!whole_library).then_some(&dirs))
+            .and_then(|mut selection| {
+                selection
+                    .tracks
+                    .retain(|(file, _)| !pending.iter().any(|x| file.starts_with(x)));
+                sync(opts, selection, &replace)
+            });
+
+        match result {
+            Ok(()) => {
+                watcher.changes.clear(&albums, now);
+                initial = false;
+                retry_at = None;
+                println!("Watching for changes...");
+            }
+            Err(e) => {
+                println!("{} {e}", "Sync failed, retrying in a minute:".red());
+                retry_at = Some(now + RETRY_DELAY);
+            }
+        }
+    }
+}
+
+/// Measures the loudness of every album with pending transcode jobs and applies it to the jobs as `mode` says.
+///
+/// Albums are measured over all of their transcoded tracks, including the ones already on the target, so their gain
+/// doesn't depend on what is left to sync. Tracks that can't be decoded are reported and left without a gain.
+fn apply_replaygain(
+    mode: ReplayGainMode,
+    units: &[LoudnessUnit],
+    jobs: &mut [TranscodeJob],
+    indicator: &ProgressBar,
+) -> Result<()> {
+    let albums = jobs
+        .iter()
+        .map(|x| units[x.loudness_unit].album.as_path())
+        .collect::<HashSet<_>>();
+    let pending = units
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| albums.contains(x.album.as_path()))
+        .map(|(i, x)| (i, x.clone()))
+        .collect::<Vec<_>>();
+
+    indicator.inc_length(pending.len() as u64);
+
+    // The tracks of an album image are measured in a single pass over the image.
+    let mut sources = HashMap::<(PathBuf, usize), Vec<(usize, Option<(f64, Option<f64>)>)>>::new();
+    for (i, unit) in pending {
+        sources
+            .entry((unit.file, unit.audio_track))
+            .or_default()
+            .push((i, unit.range));
+    }
+    let sources = sources.into_iter().collect::<Vec<_>>();
+
+    let num_threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+    let (tx, rx) = mpsc::channel();
+    let mut handles = Vec::new();
+
+    for chunk in sources.chunks((sources.len() / num_threads).max(1)) {
+        let tx = tx.clone();
+        let chunk = chunk.to_vec();
+
+        handles.push(thread::spawn(move || {
+            for ((file, audio_track), ranges) in chunk {
+                let (indices, ranges): (Vec<_>, Vec<_>) = ranges.into_iter().unzip();
+                let result = loudness::analyze(&file, audio_track, &ranges);
+                let _ = tx.send((indices, result));
+            }
+        }));
+    }
+
+    drop(tx);
+
+    let mut measured = HashMap::new();
+    for (indices, result) in rx {
+        let Some(&first) = indices.first() else {
+            continue;
+        };
+        indicator.set_message(format!("Analyzed loudness of {}", units[first].file.get_file_name()));
+        indicator.inc(indices.len() as u64);
+
+        match result {
+            Ok(loudness) => measured.extend(indices.into_iter().zip(loudness)),
+            Err(e) => indicator.suspend(|| println!("{} {e}", "Skipping loudness analysis:".yellow())),
+        }
+    }
+
+    for handle in handles {
+        if handle.join().is_err() {
+            return Err(Error::descriptive("A loudness analysis thread panicked"));
+        }
+    }
+
+    let mut album_loudness = HashMap::<&Path, Vec<&Loudness>>::new();
+    for (i, loudness) in &measured {
+        album_loudness
+            .entry(units[*i].album.as_path())
+            .or_default()
+            .push(loudness);
+    }
+
+    for job in jobs {
+        let album = units[job.loudness_unit].album.as_path();
+        let Some(gain) = measured
+            .get(&job.loudness_unit)
+            .zip(album_loudness.get(album))
+            .and_then(|(track, album)| Loudness::gain(track, album))
+        else {
+            continue;
+        };
+
+        match mode {
+            ReplayGainMode::Tags => job.metadata.extend(gain.tags(job.encoder.codec() == Codec::Opus)),
+            ReplayGainMode::Bake => job.volume = Some(gain.clipless_album_gain()),
+        }
+    }
+
     Ok(())
 }
+
+/// Rewrites every playlist in `source_dir` against the sync plan and pushes them to the target.
+///
+/// `planned` maps source relative track paths to their target relative paths. With [`PlaylistMode::TwoWay`], the
+/// playlists on the target are merged in first, see [`merge_playlist`].
+fn sync_playlists(
+    env: &SyncEnv,
+    playlist_dir: Option<&Path>,
+    mode: PlaylistMode,
+    planned: &HashMap<PathBuf, PathBuf>,
+    indicator: &ProgressBar,
+) -> Result<()> {
+    let SyncEnv {
+        fs,
+        source_dir,
+        target_dir,
+        temp_dir,
+        ignore,
+    } = *env;
+    let abs_source_dir = normalize_path(&std::path::absolute(source_dir)?);
+    let playlists = read_dir_recursively(source_dir, &Some(PlaylistFormat::EXTENSIONS.to_vec()), ignore)?;
+    let base_dir = match mode {
+        PlaylistMode::Push => None,
+        PlaylistMode::TwoWay => Some(playlist_base_dir(&abs_source_dir, target_dir)?),
+    };
+
+    let mut targets = HashMap::<PathBuf, &Path>::new();
+    for file in &playlists {
+        let rel_path = file
+            .strip_prefix(source_dir)
+            .map_err(|_| Error::descriptive("Playlist path is outside of the source directory"))?;
+        let target_path = match playlist_dir {
+            Some(dir) => dir.join(rel_path.get_file_name()),
+            None => target_dir.join(rel_path),
+        };
+
+        // Playlists from different folders share a name once flattened into the playlist directory.
+        if let Some(other) = targets.insert(target_path.clone(), rel_path) {
+            let message = format!(
+                "Playlists {} and {} would both be written to the same file, rename one of them",
+                other.display(),
+                rel_path.display()
+            );
+            return Err(Error::descriptive(message).with_context(target_path.to_string_lossy()));
+        }
+    }
+
+    indicator.inc_length(playlists.len() as u64);
+
+    for file in &playlists {
+        let rel_path = file
+            .strip_prefix(source_dir)
+            .map_err(|_| Error::descriptive("Playlist path is outside of the source directory"))?;
+        let mut playlist = Playlist::read(file)?;
+
+        let target_path = match playlist_dir {
+            Some(dir) => dir.join(rel_path.get_file_name()),
+            None => target_dir.join(rel_path),
+        };
+        let target_parent = target_path.parent().unwrap_or(target_dir);
+
+        let merged = match &base_dir {
+            Some(base_dir) => {
+                indicator.set_message(format!("Merging playlist {}", rel_path.get_file_name()));
+                let base_path = base_dir.join(rel_path);
+                let merged = merge_playlist(
+                    env,
+                    rel_path,
+                    &mut playlist,
+                    &target_path,
+                    &base_path,
+                    planned,
+                    indicator,
+                )?;
+                Some((base_path, merged))
+            }
+            None => None,
+        };
+
+        let mut dropped = Vec::new();
+        let source_playlist = abs_source_dir.join(rel_path);
+
+        playlist.entries.retain_mut(|entry| {
+            let target_rel = Playlist::resolve_entry(&source_playlist, entry)
+                .and_then(|x| x.strip_prefix(&abs_source_dir).ok().map(Path::to_path_buf))
+                .and_then(|x| planned.get(&x));
+
+            match target_rel {
+                Some(target_rel) => {
+                    let location = relative_path(target_parent, &target_dir.join(target_rel));
+                    entry.location = location.to_string_lossy().replace('\\', "/");
+                    true
+                }
+                None => {
+                    dropped.push(entry.location.clone());
+                    false
+                }
+            }
+        });
+
+        if !dropped.is_empty() {
+            let message = format!(
+                "{} dropped {} entries that are not part of the sync:",
+                rel_path.display(),
+                dropped.len().to_string().yellow()
+            );
+            indicator.suspend(|| {
+                println!("{message}");
+                for location in dropped {
+                    println!("  {}", location.dimmed());
+                }
+            });
+        }
+
+        indicator.set_message(format!("Syncing playlist {}", rel_path.get_file_name()));
+
+        let temp_path = temp_dir.join(rel_path);
+        if let Some(parent) = temp_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(&temp_path, playlist.serialize())?;
+        if let Err(e) = fs.cp(&temp_path, &target_path) {
+            let context = format!("While copying {temp_path:#?} to {target_path:#?}");
+            return Err(e.with_context(context));
+        }
+        fs::remove_file(temp_path)?;
+
+        // Only recorded once pushed, or the next merge would read the older playlist as changes made on the target.
+        if let Some((base_path, merged)) = merged {
+            write_playlist_base(&base_path, &merged)?;
+        }
+
+        indicator.inc(1);
+    }
+
+    Ok(())
+}
+
+/// Merges the changes made to a playlist on the target since the last sync into `playlist`, writing it back to the
+/// source directory if it changed. Returns the merged entries to record as the last synced version.
+///
+/// Entries are compared by their path relative to the source directory, the ones on the target being translated back
+/// through `planned`. Entries on the target that aren't part of the sync can't be translated and are dropped.
+fn merge_playlist(
+    env: &SyncEnv,
+    rel_path: &Path,
+    playlist: &mut Playlist,
+    target_path: &Path,
+    base_path: &Path,
+    planned: &HashMap<PathBuf, PathBuf>,
+    indicator: &ProgressBar,
+) -> Result<Vec<PathBuf>> {
+    let SyncEnv {
+        fs,
+        source_dir,
+        target_dir,
+        temp_dir,
+        ..
+    } = *env;
+    let abs_source_dir = normalize_path(&std::path::absolute(source_dir)?);
+    let source_playlist = abs_source_dir.join(rel_path);
+    let source_parent = source_playlist.parent().unwrap_or(&abs_source_dir);
+
+    // Entries outside of the source directory, like streams, are keyed by their location.
+    let mut entries = HashMap::<PathBuf, PlaylistEntry>::new();
+    let ours = playlist
+        .entries
+        .iter()
+        .map(|entry| {
+            let key = Playlist::resolve_entry(&source_playlist, entry)
+                .and_then(|x| x.strip_prefix(&abs_source_dir).ok().map(Path::to_path_buf))
+                .unwrap_or_else(|| PathBuf::from(&entry.location));
+            entries.entry(key.clone()).or_insert_with(|| entry.clone());
+            key
+        })
+        .collect::<Vec<_>>();
+
+    if !fs.exists(target_path)? {
+        return Ok(ours);
+    }
+
+    let device_path = temp_dir.join(rel_path);
+    if let Err(e) = fs.cp_from(target_path, &device_path) {
+        let context = format!("While copying {target_path:#?} to {device_path:#?}");
+        return Err(e.with_context(context));
+    }
+    let device = Playlist::read(&device_path)?;
+    fs::remove_file(&device_path)?;
+
+    let source_tracks = planned
+        .iter()
+        .map(|(source, target)| (target, source))
+        .collect::<HashMap<_, _>>();
+    let abs_target_dir = normalize_path(target_dir);
+    let mut theirs = Vec::new();
+    let mut untracked = Vec::new();
+
+    for entry in &device.entries {
+        let key = Playlist::resolve_entry(target_path, entry)
+            .and_then(|x| x.strip_prefix(&abs_target_dir).ok().map(Path::to_path_buf))
+            .and_then(|x| source_tracks.get(&x).copied());
+
+        match key {
+            Some(key) => {
+                entries.entry(key.clone()).or_insert_with(|| PlaylistEntry {
+                    location: relative_path(source_parent, &abs_source_dir.join(key))
+                        .to_string_lossy()
+                        .replace('\\', "/"),
+                    ..entry.clone()
+                });
+                theirs.push(key.clone());
+            }
+            None => untracked.push(entry.location.clone()),
+        }
+    }
+
+    let base = match fs::read_to_string(base_path) {
+        Ok(contents) => contents.lines().map(PathBuf::from).collect::<Vec<_>>(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(Error::from(e).with_context(base_path.to_string_lossy())),
+    };
+
+    // Entries the target never had, like tracks left out of the sync, are put back where they were so that they
+    // don't read as removed on the target.
+    let mut position = 0;
+    for key in &base {
+        if planned.contains_key(key) {
+            if let Some(at) = theirs.iter().position(|x| x == key) {
+                position = at + 1;
+            }
+        } else {
+            theirs.insert(position, key.clone());
+            position += 1;
+        }
+    }
+
+    let merged = playlist::merge(&base, &ours, &theirs);
+
+    indicator.suspend(|| {
+        if !untracked.is_empty() {
+            println!(
+                "{} on the target dropped {} entries that are not part of the sync:",
+                rel_path.display(),
+                untracked.len().to_string().yellow()
+            );
+            for location in &untracked {
+                println!("  {}", location.dimmed());
+            }
+        }
+
+        if !merged.conflicts.is_empty() {
+            println!(
+                "{} has {} conflicting changes, keeping the entries of both sides:",
+                rel_path.display(),
+                merged.conflicts.len().to_string().red()
+            );
+            for conflict in &merged.conflicts {
+                let list = |keys: &[PathBuf]| match keys {
+                    [] => "(removed)".to_string(),
+                    keys => keys
+                        .iter()
+                        .map(|x| x.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                };
+                println!("  {} {}", "library:".dimmed(), list(&conflict.ours));
+                println!("  {} {}", "target:".dimmed(), list(&conflict.theirs));
+            }
+        }
+    });
+
+    // Entries only the last synced version still has, like tracks since removed from the library, can't be written
+    // back without knowing how they were listed, and are dropped.
+    let items = merged
+        .items
+        .into_iter()
+        .filter(|x| entries.contains_key(x))
+        .collect::<Vec<_>>();
+
+    if items != ours {
+        playlist.entries = items.iter().map(|x| entries[x].clone()).collect();
+
+        let file = source_dir.join(rel_path);
+        fs::write(&file, playlist.serialize()).map_err(|e| Error::from(e).with_context(file.to_string_lossy()))?;
+        indicator.suspend(|| println!("Merged the changes made on the target into {}", rel_path.display()));
+    }
+
+    Ok(items)
+}
+
+/// The directory keeping the last synced version of the playlists of a source and target directory pair.
+fn playlist_base_dir(source_dir: &Path, target_dir: &Path) -> Result<PathBuf> {
+    let state_dir = Config::state_dir()
+        .ok_or_else(|| Error::descriptive("Two-way playlist sync needs $XDG_STATE_HOME or $HOME to be set"))?;
+
+    // FNV-1a, as the hashers of the standard library may change between releases.
+    let pair = format!("{}\n{}", source_dir.display(), target_dir.display());
+    let hash = pair.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    Ok(state_dir.join("playlists").join(format!("{hash:016x}")))
+}
+
+fn write_playlist_base(path: &Path, keys: &[PathBuf]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let contents = keys
+        .iter()
+        .map(|x| format!("{}\n", x.to_string_lossy().replace('\\', "/")))
+        .collect::<String>();
+    fs::write(path, contents).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SyncEnv, merge_playlist};
+    use crate::{playlist::Playlist, utils::fs::FSBackend, utils::ignore::Ignore};
+    use indicatif::ProgressBar;
+    use std::{
+        collections::HashMap,
+        fs,
+        path::PathBuf,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn merges_conflicts_around_tracks_missing_from_the_library() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time should be after unix epoch")
+            .as_nanos();
+        let root = std::env::temp_dir().join(format!("tsync-test-merge-{nanos}"));
+        let (source_dir, target_dir, temp_dir) = (root.join("source"), root.join("target"), root.join("temp"));
+        let rel_path = PathBuf::from("Playlists/mix.m3u8");
+
+        // `K` was in the last synced version, but no longer exists in the library.
+        fs::create_dir_all(source_dir.join("Playlists")).expect("source dir should be created");
+        fs::create_dir_all(target_dir.join("Playlists")).expect("target dir should be created");
+        fs::write(
+            source_dir.join(&rel_path),
+            "../A.flac\nhttp://radio.example/stream\n../B.flac\n",
+        )
+        .expect("library playlist should be written");
+        fs::write(target_dir.join(&rel_path), "../A.opus\n../D.opus\n../B.opus\n")
+            .expect("target playlist should be written");
+        fs::write(root.join("base"), "A.flac\nK.flac\nB.flac\n").expect("base should be written");
+
+        let planned = ["A", "B", "D"]
+            .map(|x| (PathBuf::from(format!("{x}.flac")), PathBuf::from(format!("{x}.opus"))))
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let env = SyncEnv {
+            fs: &FSBackend::None,
+            source_dir: &source_dir,
+            target_dir: &target_dir,
+            temp_dir: &temp_dir,
+            ignore: &Ignore::default(),
+        };
+        let mut playlist = Playlist::read(&source_dir.join(&rel_path)).expect("playlist should be read");
+
+        let merged = merge_playlist(
+            &env,
+            &rel_path,
+            &mut playlist,
+            &target_dir.join(&rel_path),
+            &root.join("base"),
+            &planned,
+            &ProgressBar::hidden(),
+        )
+        .expect("playlists should merge");
+
+        let locations = playlist.entries.iter().map(|x| x.location.as_str()).collect::<Vec<_>>();
+        assert!(!merged.contains(&PathBuf::from("K.flac")));
+        assert_eq!(locations.first(), Some(&"../A.flac"));
+        assert_eq!(locations.last(), Some(&"../B.flac"));
+        assert!(locations.contains(&"http://radio.example/stream") && locations.contains(&"../D.flac"));
+        assert_eq!(locations.len(), 4);
+
+        let _ = fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    commands::sync::SyncOpts,
+    errors::{Error, Result},
+};
+
+/// The configuration file, holding named sync profiles.
+///
+/// ```toml
+/// [profiles.pixel]
+/// source = "~/Music/Library"
+/// target = "/sdcard/Music/Library"
+/// serial = "1A2B3C4D"
+/// codec = "opus"
+/// bitrate = 128
+/// transcode-codecs = ["flac", "alac"]
+/// include-extras = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub profiles: HashMap<String, SyncOpts>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/tsync/config.toml`, falling back to `~/.config/tsync/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|x| PathBuf::from(x).join(".config")))?;
+
+        Some(config_dir.join("tsync").join("config.toml"))
+    }
+
+    /// `$XDG_STATE_HOME/tsync`, falling back to `~/.local/state/tsync`, where state kept between syncs is written.
+    pub fn state_dir() -> Option<PathBuf> {
+        let state_dir = std::env::var_os("XDG_STATE_HOME")
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|x| PathBuf::from(x).join(".local").join("state")))?;
+
+        Some(state_dir.join("tsync"))
+    }
+
+    /// Loads the configuration from `path`, or from the default location if it exists.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Self::default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let contents =
+            std::fs::read_to_string(&path).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))?;
+        toml::from_str(&contents).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))
+    }
+
+    pub fn profile(&self, name: &str) -> Result<SyncOpts> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| Error::descriptive("Profile does not exist in the configuration").with_context(name))?;
+
+        Ok(profile.clone().with_expanded_home())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn rejects_unknown_profile_keys() {
+        let valid = "[profiles.pixel]\ncodec = \"opus\"\nbitrate = 128\ninclude-extras = true\n";
+        let invalid = "[profiles.pixel]\ncodecs = \"opus\"\n";
+
+        let config = toml::from_str::<Config>(valid).expect("config should parse");
+        assert!(config.profile("pixel").is_ok());
+        assert!(config.profile("tablet").is_err());
+        assert!(toml::from_str::<Config>(invalid).is_err());
+    }
+}
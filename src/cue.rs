@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::errors::{Error, Result};
+
+/// CUE sheet timestamps count 75 frames a second.
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+/// A CUE sheet, describing the tracks of one or more album images.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// `REM` comments like `GENRE` and `DATE`, keyed by lowercase names.
+    pub comments: HashMap<String, String>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Start of `INDEX 01` in seconds.
+    pub start: f64,
+}
+
+/// A track cut out of an album image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueSegment {
+    /// Start in seconds.
+    pub start: f64,
+    /// Length in seconds, or `None` for the last track of the image.
+    pub duration: Option<f64>,
+    /// The file name of the track, without an extension, e.g. `01 So What`.
+    pub file_stem: String,
+    /// Tags written to the track with ffmpeg's generic keys, overriding the tags carried over from the image.
+    pub tags: Vec<(&'static str, String)>,
+}
+
+impl CueSheet {
+    pub fn parse(contents: &str) -> Result<CueSheet> {
+        let mut sheet = CueSheet::default();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim().trim_start_matches('\u{feff}');
+            let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            let context = || format!("line {}", i + 1);
+
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => sheet.files.push(CueFile {
+                    name: unquote(rest),
+                    tracks: vec![],
+                }),
+                "TRACK" => {
+                    let file = sheet
+                        .files
+                        .last_mut()
+                        .ok_or_else(|| Error::descriptive("TRACK before any FILE").with_context(context()))?;
+                    let number = rest
+                        .split_whitespace()
+                        .next()
+                        .and_then(|x| x.parse().ok())
+                        .ok_or_else(|| Error::descriptive("Invalid track number").with_context(context()))?;
+
+                    file.tracks.push(CueTrack {
+                        number,
+                        ..Default::default()
+                    });
+                }
+                "INDEX" => {
+                    let mut parts = rest.split_whitespace();
+                    let (Some(index), Some(time)) = (parts.next(), parts.next()) else {
+                        return Err(Error::descriptive("Invalid INDEX").with_context(context()));
+                    };
+                    let start = parse_time(time)
+                        .ok_or_else(|| Error::descriptive("Invalid timestamp").with_context(context()))?;
+
+                    if index.parse::<u32>().ok() == Some(1)
+                        && let Some(track) = sheet.files.last_mut().and_then(|x| x.tracks.last_mut())
+                    {
+                        track.start = start;
+                    }
+                }
+                "TITLE" | "PERFORMER" => {
+                    let value = Some(unquote(rest));
+                    let is_title = command.eq_ignore_ascii_case("TITLE");
+                    match sheet.files.last_mut().and_then(|x| x.tracks.last_mut()) {
+                        Some(track) if is_title => track.title = value,
+                        Some(track) => track.performer = value,
+                        None if is_title => sheet.title = value,
+                        None => sheet.performer = value,
+                    }
+                }
+                "REM" => {
+                    if let Some((key, value)) = rest.split_once(char::is_whitespace) {
+                        sheet.comments.insert(key.to_lowercase(), unquote(value.trim()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(sheet)
+    }
+
+    /// Reads the CUE sheet next to `image`, either `album.cue` or `album.flac.cue`.
+    ///
+    /// Returns `None` when there is no sheet, or it doesn't describe more than one track of the image.
+    pub fn read_for(image: &Path) -> Result<Option<(CueSheet, CueFile)>> {
+        let candidates = [
+            image.with_extension("cue"),
+            PathBuf::from(format!("{}.cue", image.to_string_lossy())),
+        ];
+        let Some(path) = candidates.into_iter().find(|x| x.is_file()) else {
+            return Ok(None);
+        };
+
+        let bytes = std::fs::read(&path).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))?;
+        // Older rippers write sheets in Latin-1 rather than UTF-8.
+        let contents = String::from_utf8(bytes).unwrap_or_else(|e| e.into_bytes().iter().map(|x| *x as char).collect());
+        let sheet = CueSheet::parse(&contents).map_err(|e| e.with_context(path.to_string_lossy()))?;
+
+        let image_name = image.file_name().map(|x| x.to_string_lossy()).unwrap_or_default();
+        let file = match &sheet.files[..] {
+            // Sheets are often kept after converting the image, so a single FILE entry matches regardless of its name.
+            [file] => Some(file.clone()),
+            files => files.iter().find(|x| x.name.eq_ignore_ascii_case(&image_name)).cloned(),
+        };
+
+        Ok(file.filter(|x| x.tracks.len() > 1).map(|file| (sheet, file)))
+    }
+
+    /// Cuts `file` into a segment for each of its tracks.
+    pub fn segments(&self, file: &CueFile) -> Vec<CueSegment> {
+        let total = file.tracks.len();
+
+        file.tracks
+            .iter()
+            .enumerate()
+            .map(|(i, track)| {
+                let next_start = file.tracks.get(i + 1).map(|x| x.start);
+                let title = track
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| format!("Track {:02}", track.number));
+
+                let mut tags = vec![
+                    ("title", title.clone()),
+                    ("track", format!("{}/{total}", track.number)),
+                    // Embedded sheets would otherwise be copied into every track.
+                    ("cuesheet", String::new()),
+                ];
+                if let Some(performer) = track.performer.as_ref().or(self.performer.as_ref()) {
+                    tags.push(("artist", performer.clone()));
+                }
+                if let Some(performer) = &self.performer {
+                    tags.push(("album_artist", performer.clone()));
+                }
+                if let Some(album) = &self.title {
+                    tags.push(("album", album.clone()));
+                }
+                for key in ["genre", "date"] {
+                    if let Some(value) = self.comments.get(key) {
+                        tags.push((key, value.clone()));
+                    }
+                }
+
+                CueSegment {
+                    start: track.start,
+                    duration: next_start.map(|x| x - track.start),
+                    file_stem: format!("{:02} {}", track.number, sanitize_file_name(&title)),
+                    tags,
+                }
+            })
+            .collect()
+    }
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    match value.strip_prefix('"') {
+        Some(rest) => rest.split('"').next().unwrap_or(rest).to_string(),
+        // Unquoted FILE entries are followed by their type, e.g. `FILE album.flac WAVE`.
+        None => value.split_whitespace().next().unwrap_or(value).to_string(),
+    }
+}
+
+/// Parses `mm:ss:ff` into seconds.
+fn parse_time(value: &str) -> Option<f64> {
+    let mut parts = value.split(':').map(|x| x.parse::<u32>().ok());
+    let (Some(Some(minutes)), Some(Some(seconds)), Some(Some(frames)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+
+    Some(minutes as f64 * 60.0 + seconds as f64 + frames as f64 / FRAMES_PER_SECOND)
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .trim_end_matches('.')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CueSheet;
+
+    #[test]
+    fn splits_album_images() {
+        let sheet = CueSheet::parse(
+            "\u{feff}REM GENRE Jazz\r
+REM DATE 1959\r
+PERFORMER \"Miles Davis\"\r
+TITLE \"Kind of Blue\"\r
+FILE \"Kind of Blue.wav\" WAVE\r
+  TRACK 01 AUDIO\r
+    TITLE \"So What\"\r
+    INDEX 01 00:00:00\r
+  TRACK 02 AUDIO\r
+    TITLE \"Freddie Freeloader\"\r
+    PERFORMER \"Miles Davis Sextet\"\r
+    INDEX 00 09:20:10\r
+    INDEX 01 09:22:37\r
+  TRACK 03 AUDIO\r
+    TITLE \"Blue in Green?\"\r
+    INDEX 01 19:08:00\r
+",
+        )
+        .expect("sheet should parse");
+
+        assert_eq!(sheet.title.as_deref(), Some("Kind of Blue"));
+        assert_eq!(sheet.files[0].name, "Kind of Blue.wav");
+
+        let segments = sheet.segments(&sheet.files[0]);
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].file_stem, "01 So What");
+        assert_eq!(segments[2].file_stem, "03 Blue in Green_");
+
+        let start = 9.0 * 60.0 + 22.0 + 37.0 / 75.0;
+        assert!((segments[1].start - start).abs() < 1e-9);
+        assert!((segments[1].duration.expect("middle track should end") - (19.0 * 60.0 + 8.0 - start)).abs() < 1e-9);
+        assert_eq!(segments[2].duration, None);
+        assert!(segments[1].tags.contains(&("artist", "Miles Davis Sextet".to_string())));
+        assert!(segments[1].tags.contains(&("album_artist", "Miles Davis".to_string())));
+        assert!(segments[1].tags.contains(&("genre", "Jazz".to_string())));
+
+        assert!(CueSheet::parse("TRACK 01 AUDIO").is_err());
+    }
+}
@@ -74,3 +74,36 @@ impl From<std::num::ParseIntError> for Error {
         }
     }
 }
+
+impl From<toml::de::Error> for Error {
+    fn from(error: toml::de::Error) -> Self {
+        Self {
+            type_: ErrorType::Serde,
+            message: error.to_string(),
+            context: None,
+            source: Some(Box::new(error)),
+        }
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(error: toml::ser::Error) -> Self {
+        Self {
+            type_: ErrorType::Serde,
+            message: error.to_string(),
+            context: None,
+            source: Some(Box::new(error)),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Self {
+            type_: ErrorType::Serde,
+            message: error.to_string(),
+            context: None,
+            source: Some(Box::new(error)),
+        }
+    }
+}
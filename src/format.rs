@@ -1,21 +1,77 @@
-use std::{fs::File, path::Path};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
 
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use symphonia::core::{
     codecs::{
-        CODEC_TYPE_AAC, CODEC_TYPE_ALAC, CODEC_TYPE_FLAC, CODEC_TYPE_MP3, CODEC_TYPE_OPUS, CODEC_TYPE_VORBIS, CodecType,
+        CODEC_TYPE_AAC, CODEC_TYPE_ALAC, CODEC_TYPE_FLAC, CODEC_TYPE_MONKEYS_AUDIO, CODEC_TYPE_MP3, CODEC_TYPE_NULL,
+        CODEC_TYPE_OPUS, CODEC_TYPE_VORBIS, CODEC_TYPE_WAVPACK, CodecType,
     },
     formats::{FormatOptions, Track},
     io::MediaSourceStream,
-    meta::MetadataOptions,
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey},
     probe::Hint,
 };
 
 use crate::errors::{Error, Result};
 
+pub mod container;
+pub mod encoder;
+
 #[derive(Debug)]
 pub struct TrackData {
-    pub codec: Codec,
+    pub format: SourceFormat,
+    /// Unsynchronized lyrics embedded in the file, from `LYRICS`/`UNSYNCEDLYRICS` or `USLT` tags.
+    pub lyrics: Option<String>,
+    /// Textual tags keyed by lowercase names like `albumartist` or `genre`. Keys may hold multiple values.
+    pub tags: HashMap<String, Vec<String>>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    /// Average bitrate in kbps, estimated from the file size and duration.
+    pub bitrate: Option<u32>,
+    /// The number of audio tracks in the file.
+    pub track_count: usize,
+    /// The index of the audio track the data was read from, among the audio tracks of the file.
+    pub audio_track: usize,
+}
+
+impl TrackData {
+    pub(crate) fn from_format(format: SourceFormat) -> TrackData {
+        TrackData {
+            format,
+            lyrics: None,
+            tags: HashMap::new(),
+            sample_rate: None,
+            bit_depth: None,
+            duration: None,
+            bitrate: None,
+            track_count: 1,
+            audio_track: 0,
+        }
+    }
+
+    /// Returns the values of a tag, deriving `year` from the date tags when it isn't set explicitly.
+    pub fn tag(&self, key: &str) -> Option<&[String]> {
+        if let Some(values) = self.tags.get(key) {
+            return Some(values);
+        }
+
+        match key {
+            "year" => self
+                .tags
+                .get("date")
+                .or_else(|| self.tags.get("originaldate"))
+                .map(Vec::as_slice),
+            _ => None,
+        }
+    }
 }
 
 pub fn get_track_data(path: &Path, extension: &str) -> Result<TrackData> {
@@ -29,25 +85,146 @@ pub fn get_track_data(path: &Path, extension: &str) -> Result<TrackData> {
 
     hint.with_extension(extension);
 
-    let probed = symphonia::default::get_probe()
-        .format(&hint, mss, &fmt_opts, &meta_opts)
-        .map_err(|e| Error::descriptive(format!("Failed to probe media format: {e}")).with_context(path_str.clone()))?;
+    let mut probed = match symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts) {
+        Ok(probed) => probed,
+        Err(e) => {
+            // Formats symphonia can't read are still passed to ffmpeg, so only their format is needed.
+            if let Some(format) = sniff_format(path)? {
+                return Ok(TrackData::from_format(format));
+            }
 
-    probe_track(probed.format.tracks()).map_err(|e| e.with_context(path_str))
+            let message = format!("Failed to probe media format: {e}");
+            return Err(Error::descriptive(message).with_context(path_str));
+        }
+    };
+
+    let default_id = probed.format.default_track().map(|x| x.id);
+    let mut data = probe_track(probed.format.tracks(), default_id).map_err(|e| e.with_context(path_str))?;
+    if let Some(duration) = data.duration.filter(|x| *x > 0.0)
+        && let Ok(metadata) = path.metadata()
+    {
+        data.bitrate = Some((metadata.len() as f64 * 8.0 / duration / 1000.0).round() as u32);
+    }
+
+    // Tags may live in the container (e.g. vorbis comments) or in a header preceding it (e.g. ID3v2),
+    // the container revision takes precedence when both are present.
+    if let Some(revision) = probed.format.metadata().current() {
+        read_revision(&mut data, revision);
+    }
+    if let Some(metadata) = probed.metadata.get()
+        && let Some(revision) = metadata.current()
+    {
+        read_revision(&mut data, revision);
+    }
+
+    Ok(data)
 }
 
-fn probe_track(tracks: &[Track]) -> Result<TrackData> {
-    let track = tracks
+fn read_revision(data: &mut TrackData, revision: &MetadataRevision) {
+    for tag in revision.tags() {
+        if tag.std_key == Some(StandardTagKey::Lyrics) {
+            let lyrics = tag.value.to_string();
+            if data.lyrics.is_none() && !lyrics.trim().is_empty() {
+                data.lyrics = Some(lyrics);
+            }
+
+            continue;
+        }
+
+        let key = match tag.std_key.and_then(standard_tag_name) {
+            Some(name) => name.to_string(),
+            None => tag.key.to_lowercase(),
+        };
+
+        let values = data.tags.entry(key).or_default();
+        let value = tag.value.to_string();
+        if !value.is_empty() && !values.contains(&value) {
+            values.push(value);
+        }
+    }
+}
+
+fn standard_tag_name(key: StandardTagKey) -> Option<&'static str> {
+    let name = match key {
+        StandardTagKey::TrackTitle => "title",
+        StandardTagKey::Artist => "artist",
+        StandardTagKey::Album => "album",
+        StandardTagKey::AlbumArtist => "albumartist",
+        StandardTagKey::Genre => "genre",
+        StandardTagKey::Date => "date",
+        StandardTagKey::OriginalDate => "originaldate",
+        StandardTagKey::TrackNumber => "tracknumber",
+        StandardTagKey::TrackTotal => "tracktotal",
+        StandardTagKey::DiscNumber => "discnumber",
+        StandardTagKey::DiscTotal => "disctotal",
+        StandardTagKey::Composer => "composer",
+        StandardTagKey::Conductor => "conductor",
+        StandardTagKey::Label => "label",
+        StandardTagKey::Rating => "rating",
+        StandardTagKey::Comment => "comment",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+/// Whether a track holds audio, counting audio in codecs symphonia can't decode so that the tracks are numbered like
+/// ffmpeg's `0:a:N`.
+///
+/// Cover art and video streams have neither a codec symphonia knows nor a sample rate or channels.
+pub(crate) fn is_audio(track: &Track) -> bool {
+    let params = &track.codec_params;
+    params.codec != CODEC_TYPE_NULL || params.sample_rate.is_some() || params.channels.is_some()
+}
+
+/// Picks the audio track to read, preferring the container's default track over the first supported one.
+fn probe_track(tracks: &[Track], default_id: Option<u32>) -> Result<TrackData> {
+    let audio_tracks = tracks.iter().filter(|x| is_audio(x)).collect::<Vec<_>>();
+    let first = audio_tracks
         .first()
-        .ok_or_else(|| Error::descriptive("Track metadata is not available"))?;
-    let codec_type = track.codec_params.codec;
-    let codec = Codec::from_symphonia(codec_type)
-        .ok_or_else(|| Error::descriptive(format!("Unsupported codec: {codec_type:#?}")))?;
+        .ok_or_else(|| Error::descriptive("No audio track is available"))?;
 
-    Ok(TrackData { codec })
+    fn supported((i, track): (usize, &Track)) -> Option<(usize, &Track, SourceFormat)> {
+        SourceFormat::from_symphonia(track.codec_params.codec).map(|format| (i, track, format))
+    }
+
+    let (audio_track, track, format) = audio_tracks
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(_, x)| Some(x.id) == default_id)
+        .find_map(supported)
+        .or_else(|| audio_tracks.iter().copied().enumerate().find_map(supported))
+        .ok_or_else(|| {
+            let codec_type = first.codec_params.codec;
+            Error::descriptive(format!("Unsupported codec: {codec_type:#?}"))
+        })?;
+
+    let params = &track.codec_params;
+    let duration = match (params.n_frames, params.time_base, params.sample_rate) {
+        (Some(frames), Some(time_base), _) => {
+            let time = time_base.calc_time(frames);
+            Some(time.seconds as f64 + time.frac)
+        }
+        (Some(frames), None, Some(rate)) => Some(frames as f64 / rate as f64),
+        _ => None,
+    };
+
+    Ok(TrackData {
+        format,
+        lyrics: None,
+        tags: HashMap::new(),
+        sample_rate: params.sample_rate,
+        bit_depth: params.bits_per_sample,
+        duration,
+        bitrate: None,
+        track_count: audio_tracks.len(),
+        audio_track,
+    })
 }
 
-#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Copy, ValueEnum, Serialize, Deserialize, PartialEq, PartialOrd)]
+#[serde(rename_all = "kebab-case")]
 pub enum Codec {
     Opus,
     Vorbis,
@@ -59,29 +236,8 @@ pub enum Codec {
 }
 
 impl Codec {
-    pub fn from_symphonia(codec_type: CodecType) -> Option<Codec> {
-        let codec = match codec_type {
-            CODEC_TYPE_OPUS => Codec::Opus,
-            CODEC_TYPE_VORBIS => Codec::Vorbis,
-            CODEC_TYPE_MP3 => Codec::Mp3,
-            CODEC_TYPE_AAC => Codec::AacLc,
-            CODEC_TYPE_FLAC => Codec::Flac,
-            CODEC_TYPE_ALAC => Codec::Alac,
-            _ => return None,
-        };
-
-        Some(codec)
-    }
-
-    pub fn extenstion_str(&self) -> &'static str {
-        match *self {
-            Codec::Opus => "opus",
-            Codec::Vorbis => "ogg",
-            Codec::Mp3 => "mp3",
-            Codec::AacLc => "m4a",
-            Codec::Flac => "flac",
-            Codec::Alac => "m4a",
-        }
+    pub fn is_lossless(&self) -> bool {
+        matches!(self, Codec::Flac | Codec::Alac)
     }
 
     pub fn ffmpeg_lib(&self) -> &'static str {
@@ -94,38 +250,168 @@ impl Codec {
             Codec::Alac => "alac",
         }
     }
+}
 
-    pub fn matching_bitrate(&self, optional: Option<u32>) -> Result<u32> {
-        match optional {
-            Some(opt_bitrate) => {
-                let (min, max) = match self {
-                    Codec::Opus => (6, 256),
-                    Codec::Vorbis => (64, 500),
-                    Codec::Mp3 => (32, 320),
-                    Codec::AacLc => (32, 320),
-                    Codec::Flac => (128, 1024),
-                    Codec::Alac => (128, 1024),
-                };
-
-                if opt_bitrate < min || opt_bitrate > max {
-                    let message = format!("Bitrate must be between {min} and {max} for format {self:?}");
-                    return Err(Error::descriptive(message));
-                }
-
-                Ok(opt_bitrate)
-            }
-            None => {
-                let default = match self {
-                    Codec::Opus => 128,
-                    Codec::Vorbis => 192,
-                    Codec::Mp3 => 192,
-                    Codec::AacLc => 192,
-                    Codec::Flac => 512,
-                    Codec::Alac => 512,
-                };
-
-                Ok(default)
+/// The format of a source track. Every output [`Codec`] is a source format as well, but formats like PCM or DSD can only
+/// be transcoded from.
+#[derive(Debug, Clone, Copy, ValueEnum, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceFormat {
+    Opus,
+    Vorbis,
+    Mp3,
+    AacLc,
+
+    Flac,
+    Alac,
+    /// Uncompressed PCM, usually in WAV or AIFF files.
+    #[value(alias = "wav", alias = "aiff")]
+    #[serde(alias = "wav", alias = "aiff")]
+    Pcm,
+    WavPack,
+    Ape,
+    /// Direct Stream Digital, in DSF or DFF files.
+    Dsd,
+}
+
+impl SourceFormat {
+    pub fn from_symphonia(codec_type: CodecType) -> Option<SourceFormat> {
+        let format = match codec_type {
+            CODEC_TYPE_OPUS => SourceFormat::Opus,
+            CODEC_TYPE_VORBIS => SourceFormat::Vorbis,
+            CODEC_TYPE_MP3 => SourceFormat::Mp3,
+            CODEC_TYPE_AAC => SourceFormat::AacLc,
+            CODEC_TYPE_FLAC => SourceFormat::Flac,
+            CODEC_TYPE_ALAC => SourceFormat::Alac,
+            CODEC_TYPE_WAVPACK => SourceFormat::WavPack,
+            CODEC_TYPE_MONKEYS_AUDIO => SourceFormat::Ape,
+            x if symphonia::default::get_codecs()
+                .get_codec(x)
+                .is_some_and(|x| x.short_name.starts_with("pcm_")) =>
+            {
+                SourceFormat::Pcm
             }
+            _ => return None,
+        };
+
+        Some(format)
+    }
+
+    /// The output codec matching this format, if it can be encoded into.
+    pub fn codec(&self) -> Option<Codec> {
+        let codec = match self {
+            SourceFormat::Opus => Codec::Opus,
+            SourceFormat::Vorbis => Codec::Vorbis,
+            SourceFormat::Mp3 => Codec::Mp3,
+            SourceFormat::AacLc => Codec::AacLc,
+            SourceFormat::Flac => Codec::Flac,
+            SourceFormat::Alac => Codec::Alac,
+            SourceFormat::Pcm | SourceFormat::WavPack | SourceFormat::Ape | SourceFormat::Dsd => return None,
+        };
+
+        Some(codec)
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        !matches!(
+            self,
+            SourceFormat::Opus | SourceFormat::Vorbis | SourceFormat::Mp3 | SourceFormat::AacLc
+        )
+    }
+}
+
+impl From<Codec> for SourceFormat {
+    fn from(value: Codec) -> Self {
+        match value {
+            Codec::Opus => SourceFormat::Opus,
+            Codec::Vorbis => SourceFormat::Vorbis,
+            Codec::Mp3 => SourceFormat::Mp3,
+            Codec::AacLc => SourceFormat::AacLc,
+            Codec::Flac => SourceFormat::Flac,
+            Codec::Alac => SourceFormat::Alac,
         }
     }
 }
+
+/// Detects the formats symphonia can't probe from their magic bytes.
+fn sniff_format(path: &Path) -> Result<Option<SourceFormat>> {
+    let mut magic = [0; 4];
+    let mut file = File::open(path).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::from(e).with_context(path.to_string_lossy())),
+    }
+
+    let format = match &magic {
+        b"wvpk" => SourceFormat::WavPack,
+        b"MAC " => SourceFormat::Ape,
+        b"DSD " | b"FRM8" => SourceFormat::Dsd,
+        _ => return Ok(None),
+    };
+
+    Ok(Some(format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SourceFormat, probe_track, sniff_format};
+    use symphonia::core::{
+        codecs::{
+            CODEC_TYPE_AAC, CODEC_TYPE_ALAC, CODEC_TYPE_NULL, CODEC_TYPE_PCM_S24LE, CODEC_TYPE_WMA, CodecParameters,
+        },
+        formats::Track,
+    };
+
+    #[test]
+    fn detects_source_formats() {
+        assert_eq!(
+            SourceFormat::from_symphonia(CODEC_TYPE_PCM_S24LE),
+            Some(SourceFormat::Pcm)
+        );
+        assert_eq!(SourceFormat::from_symphonia(CODEC_TYPE_WMA), None);
+
+        let path = std::env::temp_dir().join(format!("tsync-sniff-{}.dsf", std::process::id()));
+        std::fs::write(&path, b"DSD \x1c\0\0\0").expect("test file should be written");
+        let format = sniff_format(&path).expect("test file should be readable");
+        std::fs::remove_file(&path).expect("test file should be removed");
+
+        assert_eq!(format, Some(SourceFormat::Dsd));
+    }
+
+    #[test]
+    fn selects_the_default_audio_track() {
+        let track = |id, codec| Track::new(id, CodecParameters::new().for_codec(codec).clone());
+        // A cover art stream followed by two audio tracks, as found in some MP4 files.
+        let tracks = [
+            track(1, CODEC_TYPE_NULL),
+            track(2, CODEC_TYPE_AAC),
+            track(3, CODEC_TYPE_ALAC),
+        ];
+
+        let data = probe_track(&tracks, Some(3)).expect("default track should be read");
+        assert_eq!(
+            (data.format, data.audio_track, data.track_count),
+            (SourceFormat::Alac, 1, 2)
+        );
+
+        let data = probe_track(&tracks, Some(1)).expect("first audio track should be read");
+        assert_eq!((data.format, data.audio_track), (SourceFormat::AacLc, 0));
+
+        assert!(probe_track(&tracks[..1], Some(1)).is_err());
+
+        // Audio in a codec symphonia can't decode still counts towards ffmpeg's audio stream numbering.
+        let mut unsupported = CodecParameters::new();
+        unsupported.with_sample_rate(48000);
+        let tracks = [
+            Track::new(1, unsupported),
+            track(2, CODEC_TYPE_NULL),
+            track(3, CODEC_TYPE_AAC),
+        ];
+        let data = probe_track(&tracks, None).expect("supported audio track should be read");
+        assert_eq!(
+            (data.format, data.audio_track, data.track_count),
+            (SourceFormat::AacLc, 1, 2)
+        );
+    }
+}
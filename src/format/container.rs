@@ -0,0 +1,124 @@
+use crate::format::{Codec, SourceFormat};
+
+/// Audio containers, see [`CONTAINERS`] for the formats they hold and the extensions they use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Ogg,
+    Mp4,
+    Matroska,
+    /// Raw AAC in ADTS frames.
+    Adts,
+    /// Raw MPEG audio frames, usually preceded by ID3 tags.
+    MpegAudio,
+    Flac,
+    Wav,
+    Aiff,
+    WavPack,
+    Ape,
+    Dsf,
+    Dff,
+}
+
+/// Every container tsync reads, the extensions used for it and the formats it can hold.
+///
+/// Files are discovered by these extensions and classified by the format probed from their contents afterwards, so
+/// e.g. an `.m4a` file can be either AAC or ALAC, and an `.ogg` file either Vorbis, Opus or FLAC.
+pub const CONTAINERS: &[(Container, &[&str], &[SourceFormat])] = &[
+    (
+        Container::Ogg,
+        &["ogg", "oga", "opus"],
+        &[SourceFormat::Vorbis, SourceFormat::Opus, SourceFormat::Flac],
+    ),
+    (
+        Container::Mp4,
+        &["m4a", "m4b", "mp4"],
+        &[SourceFormat::AacLc, SourceFormat::Alac],
+    ),
+    (
+        Container::Matroska,
+        &["mka"],
+        &[
+            SourceFormat::Opus,
+            SourceFormat::Vorbis,
+            SourceFormat::AacLc,
+            SourceFormat::Flac,
+            SourceFormat::Alac,
+            SourceFormat::Mp3,
+        ],
+    ),
+    (Container::Adts, &["aac"], &[SourceFormat::AacLc]),
+    (Container::MpegAudio, &["mp3"], &[SourceFormat::Mp3]),
+    (Container::Flac, &["flac"], &[SourceFormat::Flac]),
+    (Container::Wav, &["wav"], &[SourceFormat::Pcm]),
+    (Container::Aiff, &["aif", "aiff", "aifc"], &[SourceFormat::Pcm]),
+    (Container::WavPack, &["wv"], &[SourceFormat::WavPack]),
+    (Container::Ape, &["ape"], &[SourceFormat::Ape]),
+    (Container::Dsf, &["dsf"], &[SourceFormat::Dsd]),
+    (Container::Dff, &["dff"], &[SourceFormat::Dsd]),
+];
+
+impl Container {
+    pub fn extensions(&self) -> &'static [&'static str] {
+        CONTAINERS
+            .iter()
+            .find(|(container, _, _)| container == self)
+            .map(|(_, extensions, _)| *extensions)
+            .unwrap_or_default()
+    }
+}
+
+impl Codec {
+    /// The container and extension transcoded tracks are written with.
+    pub fn output_container(&self) -> (Container, &'static str) {
+        match self {
+            Codec::Opus => (Container::Ogg, "opus"),
+            Codec::Vorbis => (Container::Ogg, "ogg"),
+            Codec::Mp3 => (Container::MpegAudio, "mp3"),
+            Codec::AacLc => (Container::Mp4, "m4a"),
+            Codec::Flac => (Container::Flac, "flac"),
+            Codec::Alac => (Container::Mp4, "m4a"),
+        }
+    }
+}
+
+impl SourceFormat {
+    /// The extensions of every container that can hold this format.
+    pub fn extensions(&self) -> impl Iterator<Item = &'static str> {
+        CONTAINERS
+            .iter()
+            .filter(|(_, _, formats)| formats.contains(self))
+            .flat_map(|(_, extensions, _)| extensions.iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CONTAINERS, Container};
+    use crate::format::{Codec, SourceFormat};
+
+    #[test]
+    fn maps_formats_to_containers() {
+        let opus = SourceFormat::Opus.extensions().collect::<Vec<_>>();
+        assert!(opus.contains(&"ogg") && opus.contains(&"opus") && opus.contains(&"mka"));
+        assert!(SourceFormat::Alac.extensions().any(|x| x == "m4b"));
+
+        for codec in [
+            Codec::Opus,
+            Codec::Vorbis,
+            Codec::Mp3,
+            Codec::AacLc,
+            Codec::Flac,
+            Codec::Alac,
+        ] {
+            let (container, extension) = codec.output_container();
+            assert!(container.extensions().contains(&extension));
+            assert!(
+                CONTAINERS
+                    .iter()
+                    .any(|(x, _, formats)| *x == container && formats.contains(&SourceFormat::from(codec)))
+            );
+        }
+
+        assert_eq!(Container::Aiff.extensions(), ["aif", "aiff", "aifc"]);
+    }
+}
@@ -0,0 +1,457 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{Error, Result},
+    format::{Codec, TrackData},
+};
+
+/// How the bitrate of an encoder is managed. Only applies to opus and mp3.
+#[derive(Debug, Clone, Copy, ValueEnum, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BitrateMode {
+    /// Variable bitrate, averaging the target bitrate.
+    Vbr,
+    /// Constrained variable bitrate, staying close to the target bitrate. Opus only.
+    Cvbr,
+    /// Constant bitrate.
+    Cbr,
+}
+
+/// Validated settings of the encoder a track is transcoded with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoder {
+    Opus {
+        bitrate: u32,
+        mode: BitrateMode,
+    },
+    /// `-q` levels from -1 to 10, or an average bitrate.
+    Vorbis {
+        quality: Option<f32>,
+        bitrate: Option<u32>,
+    },
+    /// LAME's `-V` levels from 0 (best) to 9, or a constant/average bitrate.
+    Mp3 {
+        quality: Option<u8>,
+        bitrate: Option<u32>,
+        mode: BitrateMode,
+    },
+    AacLc {
+        bitrate: u32,
+    },
+    /// Compression levels from 0 (fastest) to 12 (smallest).
+    Flac {
+        compression: u8,
+    },
+    Alac,
+}
+
+impl Encoder {
+    /// Builds the encoder settings of `codec`, falling back to sensible defaults for the values that aren't given.
+    ///
+    /// `quality` is the `-q` level for vorbis, the `-V` level for mp3 and the compression level for flac.
+    pub fn new(codec: Codec, bitrate: Option<u32>, quality: Option<f32>, mode: Option<BitrateMode>) -> Result<Encoder> {
+        let context = format!("{codec:?}");
+        let check_bitrate = |bitrate: u32, min: u32, max: u32| {
+            if bitrate < min || bitrate > max {
+                let message = format!("Bitrate must be between {min} and {max}");
+                return Err(Error::descriptive(message).with_context(context.clone()));
+            }
+
+            Ok(bitrate)
+        };
+        let check_quality = |quality: f32, min: f32, max: f32, integer: bool| {
+            if quality < min || quality > max || (integer && quality.fract() != 0.0) {
+                let kind = if integer { "a whole number" } else { "a number" };
+                let message = format!("Quality must be {kind} between {min} and {max}");
+                return Err(Error::descriptive(message).with_context(context.clone()));
+            }
+
+            Ok(quality)
+        };
+        let unsupported = |option: &str| {
+            let message = format!("The {option} option is not supported by this codec");
+            Err(Error::descriptive(message).with_context(context.clone()))
+        };
+
+        if mode.is_some() && !matches!(codec, Codec::Opus | Codec::Mp3) {
+            return unsupported("bitrate mode");
+        }
+
+        let encoder = match codec {
+            Codec::Opus => {
+                if quality.is_some() {
+                    return unsupported("quality");
+                }
+
+                Encoder::Opus {
+                    bitrate: check_bitrate(bitrate.unwrap_or(128), 6, 256)?,
+                    mode: mode.unwrap_or(BitrateMode::Vbr),
+                }
+            }
+            Codec::Vorbis => match (quality, bitrate) {
+                (Some(_), Some(_)) => {
+                    let message = "Quality and bitrate cannot be used together";
+                    return Err(Error::descriptive(message).with_context(context));
+                }
+                (_, Some(bitrate)) => Encoder::Vorbis {
+                    quality: None,
+                    bitrate: Some(check_bitrate(bitrate, 64, 500)?),
+                },
+                (quality, None) => Encoder::Vorbis {
+                    quality: Some(check_quality(quality.unwrap_or(6.0), -1.0, 10.0, false)?),
+                    bitrate: None,
+                },
+            },
+            Codec::Mp3 => match (quality, bitrate, mode) {
+                (Some(_), Some(_), _) => {
+                    let message = "Quality and bitrate cannot be used together";
+                    return Err(Error::descriptive(message).with_context(context));
+                }
+                (Some(_), None, Some(BitrateMode::Cbr)) => {
+                    let message = "Quality levels are variable bitrate, pass a bitrate for constant bitrate";
+                    return Err(Error::descriptive(message).with_context(context));
+                }
+                (_, _, Some(BitrateMode::Cvbr)) => return unsupported("cvbr bitrate mode"),
+                (None, Some(bitrate), mode) => Encoder::Mp3 {
+                    quality: None,
+                    bitrate: Some(check_bitrate(bitrate, 32, 320)?),
+                    mode: mode.unwrap_or(BitrateMode::Cbr),
+                },
+                (quality, None, _) => Encoder::Mp3 {
+                    quality: Some(check_quality(quality.unwrap_or(2.0), 0.0, 9.0, true)? as u8),
+                    bitrate: None,
+                    mode: BitrateMode::Vbr,
+                },
+            },
+            Codec::AacLc => {
+                if quality.is_some() {
+                    return unsupported("quality");
+                }
+
+                Encoder::AacLc {
+                    bitrate: check_bitrate(bitrate.unwrap_or(192), 32, 320)?,
+                }
+            }
+            Codec::Flac => {
+                if bitrate.is_some() {
+                    return unsupported("bitrate");
+                }
+
+                Encoder::Flac {
+                    compression: check_quality(quality.unwrap_or(5.0), 0.0, 12.0, true)? as u8,
+                }
+            }
+            Codec::Alac => {
+                if bitrate.is_some() {
+                    return unsupported("bitrate");
+                }
+                if quality.is_some() {
+                    return unsupported("quality");
+                }
+
+                Encoder::Alac
+            }
+        };
+
+        Ok(encoder)
+    }
+
+    pub fn codec(&self) -> Codec {
+        match self {
+            Encoder::Opus { .. } => Codec::Opus,
+            Encoder::Vorbis { .. } => Codec::Vorbis,
+            Encoder::Mp3 { .. } => Codec::Mp3,
+            Encoder::AacLc { .. } => Codec::AacLc,
+            Encoder::Flac { .. } => Codec::Flac,
+            Encoder::Alac => Codec::Alac,
+        }
+    }
+
+    /// The average bitrate in kbps of the encoder's output, estimated for the quality levels of vorbis and mp3.
+    ///
+    /// Lossless encoders have none, as their output depends on the source.
+    pub fn nominal_bitrate(&self) -> Option<u32> {
+        // Typical averages of `-q -1..=10` for vorbis and `-V 0..=9` for mp3.
+        const VORBIS: [u32; 12] = [45, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 500];
+        const MP3: [u32; 10] = [245, 225, 190, 175, 165, 130, 115, 100, 85, 65];
+
+        match *self {
+            Encoder::Opus { bitrate, .. } | Encoder::AacLc { bitrate } => Some(bitrate),
+            Encoder::Vorbis {
+                bitrate: Some(bitrate), ..
+            }
+            | Encoder::Mp3 {
+                bitrate: Some(bitrate), ..
+            } => Some(bitrate),
+            Encoder::Vorbis { quality, .. } => {
+                let quality = quality.unwrap_or(6.0).clamp(-1.0, 10.0);
+                let (low, high) = (
+                    VORBIS[(quality.floor() + 1.0) as usize],
+                    VORBIS[(quality.ceil() + 1.0) as usize],
+                );
+                Some(low + ((high - low) as f32 * (quality - quality.floor())) as u32)
+            }
+            Encoder::Mp3 { quality, .. } => Some(MP3[quality.unwrap_or(2).min(9) as usize]),
+            Encoder::Flac { .. } | Encoder::Alac => None,
+        }
+    }
+
+    /// The same encoder targeting `bitrate` instead, keeping its bitrate mode. Quality levels of mp3 turn into an
+    /// average bitrate.
+    pub fn with_bitrate(&self, bitrate: u32) -> Result<Encoder> {
+        let mode = match *self {
+            Encoder::Opus { mode, .. } => Some(mode),
+            Encoder::Mp3 { bitrate: None, .. } => Some(BitrateMode::Vbr),
+            Encoder::Mp3 { mode, .. } => Some(mode),
+            _ => None,
+        };
+
+        Encoder::new(self.codec(), Some(bitrate), None, mode)
+    }
+
+    /// Arguments passed to `opusenc`.
+    pub fn opusenc_args(&self) -> Vec<String> {
+        match self {
+            Encoder::Opus { bitrate, mode } => {
+                let mode = match mode {
+                    BitrateMode::Vbr => "--vbr",
+                    BitrateMode::Cvbr => "--cvbr",
+                    BitrateMode::Cbr => "--hard-cbr",
+                };
+
+                vec!["--bitrate".to_string(), bitrate.to_string(), mode.to_string()]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Arguments passed to `ffmpeg` after the input, selecting and configuring the encoder.
+    pub fn ffmpeg_args(&self) -> Vec<String> {
+        let mut args = vec!["-c:a".to_string(), self.codec().ffmpeg_lib().to_string()];
+
+        match *self {
+            Encoder::Opus { bitrate, mode } => {
+                let vbr = match mode {
+                    BitrateMode::Vbr => "on",
+                    BitrateMode::Cvbr => "constrained",
+                    BitrateMode::Cbr => "off",
+                };
+
+                args.extend([
+                    "-b:a".to_string(),
+                    format!("{bitrate}K"),
+                    "-vbr".to_string(),
+                    vbr.to_string(),
+                ]);
+            }
+            Encoder::Vorbis { quality, bitrate } => match (quality, bitrate) {
+                (_, Some(bitrate)) => args.extend(["-b:a".to_string(), format!("{bitrate}K")]),
+                (Some(quality), None) => args.extend(["-q:a".to_string(), quality.to_string()]),
+                (None, None) => {}
+            },
+            Encoder::Mp3 { quality, bitrate, mode } => match (quality, bitrate) {
+                (_, Some(bitrate)) => {
+                    if mode == BitrateMode::Vbr {
+                        args.extend(["-abr".to_string(), "1".to_string()]);
+                    }
+                    args.extend(["-b:a".to_string(), format!("{bitrate}K")]);
+                }
+                (Some(quality), None) => args.extend(["-q:a".to_string(), quality.to_string()]),
+                (None, None) => {}
+            },
+            Encoder::AacLc { bitrate } => args.extend(["-b:a".to_string(), format!("{bitrate}K")]),
+            Encoder::Flac { compression } => args.extend(["-compression_level".to_string(), compression.to_string()]),
+            Encoder::Alac => {}
+        }
+
+        args
+    }
+}
+
+/// Caps on the sample rate and bit depth of lossless outputs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LosslessLimits {
+    pub max_sample_rate: Option<u32>,
+    pub max_bit_depth: Option<u32>,
+}
+
+/// A sample rate and bit depth conversion applied while transcoding, dithering when reducing the bit depth.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Resample {
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+}
+
+impl LosslessLimits {
+    pub fn new(max_sample_rate: Option<u32>, max_bit_depth: Option<u32>) -> Result<LosslessLimits> {
+        if let Some(rate) = max_sample_rate
+            && rate < 8000
+        {
+            return Err(Error::descriptive("Maximum sample rate must be at least 8000").with_context(rate.to_string()));
+        }
+
+        if let Some(depth) = max_bit_depth
+            && depth != 16
+            && depth != 24
+        {
+            return Err(Error::descriptive("Maximum bit depth must be either 16 or 24").with_context(depth.to_string()));
+        }
+
+        Ok(LosslessLimits {
+            max_sample_rate,
+            max_bit_depth,
+        })
+    }
+
+    /// Returns the conversion bringing `track` within the limits, or `None` if it already is.
+    ///
+    /// Sample rates are halved while they exceed the cap to stay within the same family, so 88.2kHz becomes 44.1kHz
+    /// instead of 48kHz, falling back to the cap itself.
+    pub fn resample(&self, track: &TrackData) -> Option<Resample> {
+        let sample_rate = match (track.sample_rate, self.max_sample_rate) {
+            (Some(rate), Some(max)) if rate > max => {
+                let mut target = rate;
+                while target > max && target % 2 == 0 {
+                    target /= 2;
+                }
+
+                Some(if target > max { max } else { target })
+            }
+            _ => None,
+        };
+        let bit_depth = match (track.bit_depth, self.max_bit_depth) {
+            (Some(depth), Some(max)) if depth > max => Some(max),
+            _ => None,
+        };
+
+        if sample_rate.is_none() && bit_depth.is_none() {
+            return None;
+        }
+
+        Some(Resample { sample_rate, bit_depth })
+    }
+}
+
+impl Resample {
+    /// The `aresample` filter, combined with any other filters into a single `-af` chain.
+    pub fn ffmpeg_filter(&self) -> String {
+        let mut filter = vec![];
+        if let Some(rate) = self.sample_rate {
+            filter.push(format!("osr={rate}"));
+        }
+
+        match self.bit_depth {
+            Some(16) => {
+                filter.push("osf=s16".to_string());
+                filter.push("dither_method=triangular_hp".to_string());
+            }
+            Some(_) => filter.push("osf=s32".to_string()),
+            None => {}
+        }
+
+        format!("aresample={}", filter.join(":"))
+    }
+
+    /// Arguments passed to `ffmpeg` after the encoder arguments and filters.
+    pub fn ffmpeg_args(&self) -> Vec<String> {
+        match self.bit_depth {
+            Some(depth) if depth != 16 => vec!["-bits_per_raw_sample".to_string(), depth.to_string()],
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BitrateMode, Encoder, LosslessLimits, Resample};
+    use crate::format::{Codec, SourceFormat, TrackData};
+
+    #[test]
+    fn validates_settings_per_codec() {
+        assert_eq!(
+            Encoder::new(Codec::Opus, None, None, None).expect("opus defaults should be valid"),
+            Encoder::Opus {
+                bitrate: 128,
+                mode: BitrateMode::Vbr
+            }
+        );
+        assert_eq!(
+            Encoder::new(Codec::Mp3, None, Some(0.0), None).expect("mp3 V0 should be valid"),
+            Encoder::Mp3 {
+                quality: Some(0),
+                bitrate: None,
+                mode: BitrateMode::Vbr
+            }
+        );
+        assert!(Encoder::new(Codec::Opus, Some(512), None, None).is_err());
+        assert!(Encoder::new(Codec::Opus, None, Some(5.0), None).is_err());
+        assert!(Encoder::new(Codec::Vorbis, Some(192), Some(6.0), None).is_err());
+        assert!(Encoder::new(Codec::Mp3, None, Some(2.5), None).is_err());
+        assert!(Encoder::new(Codec::Mp3, None, None, Some(BitrateMode::Cvbr)).is_err());
+        assert!(Encoder::new(Codec::Flac, Some(512), None, None).is_err());
+        assert!(Encoder::new(Codec::Flac, None, Some(13.0), None).is_err());
+        assert!(Encoder::new(Codec::AacLc, None, None, Some(BitrateMode::Cbr)).is_err());
+    }
+
+    #[test]
+    fn resamples_within_the_same_family() {
+        let limits = LosslessLimits::new(Some(48000), Some(16)).expect("limits should be valid");
+        let track = |sample_rate: u32, bit_depth: u32| TrackData {
+            sample_rate: Some(sample_rate),
+            bit_depth: Some(bit_depth),
+            ..TrackData::from_format(SourceFormat::Flac)
+        };
+
+        assert_eq!(limits.resample(&track(44100, 16)), None);
+        assert_eq!(
+            limits.resample(&track(88200, 24)),
+            Some(Resample {
+                sample_rate: Some(44100),
+                bit_depth: Some(16)
+            })
+        );
+        assert_eq!(
+            limits.resample(&track(192000, 16)),
+            Some(Resample {
+                sample_rate: Some(48000),
+                bit_depth: None
+            })
+        );
+        assert_eq!(
+            limits.resample(&track(96000, 24)).map(|x| x.ffmpeg_filter()),
+            Some("aresample=osr=48000:osf=s16:dither_method=triangular_hp".to_string())
+        );
+        assert!(LosslessLimits::new(None, Some(20)).is_err());
+    }
+
+    #[test]
+    fn builds_encoder_arguments() {
+        let mp3 = Encoder::new(Codec::Mp3, Some(320), None, None).expect("mp3 cbr should be valid");
+        assert_eq!(mp3.ffmpeg_args(), ["-c:a", "libmp3lame", "-b:a", "320K"]);
+
+        let vorbis = Encoder::new(Codec::Vorbis, None, Some(5.0), None).expect("vorbis q5 should be valid");
+        assert_eq!(vorbis.ffmpeg_args(), ["-c:a", "libvorbis", "-q:a", "5"]);
+
+        let opus = Encoder::new(Codec::Opus, Some(96), None, Some(BitrateMode::Cvbr)).expect("opus should be valid");
+        assert_eq!(opus.opusenc_args(), ["--bitrate", "96", "--cvbr"]);
+
+        assert_eq!(vorbis.nominal_bitrate(), Some(160));
+        let interpolated = |quality: f32| {
+            Encoder::new(Codec::Vorbis, None, Some(quality), None)
+                .expect("vorbis quality should be valid")
+                .nominal_bitrate()
+        };
+        assert_eq!(interpolated(5.5), Some(176));
+        assert_eq!(interpolated(-0.25), Some(59));
+        let v2 = Encoder::new(Codec::Mp3, None, None, None).expect("mp3 V2 should be valid");
+        assert_eq!(v2.nominal_bitrate(), Some(190));
+        assert_eq!(
+            v2.with_bitrate(128)
+                .map(|x| x.ffmpeg_args())
+                .expect("mp3 at 128K should be valid"),
+            ["-c:a", "libmp3lame", "-abr", "1", "-b:a", "128K"]
+        );
+        assert!(vorbis.with_bitrate(48).is_err());
+    }
+}
@@ -0,0 +1,411 @@
+use std::{f64::consts::PI, fs::File, io, path::Path};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use symphonia::core::{
+    audio::SampleBuffer, codecs::DecoderOptions, errors::Error as SymphoniaError, formats::FormatOptions,
+    io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+
+use crate::{
+    errors::{Error, Result},
+    format::is_audio,
+};
+
+/// The loudness ReplayGain 2.0 normalizes to.
+const REPLAYGAIN_REFERENCE: f64 = -18.0;
+/// The loudness R128 gain tags in Opus files are relative to.
+const R128_REFERENCE: f64 = -23.0;
+
+/// How loudness analysis is applied to transcoded tracks.
+#[derive(Debug, Clone, Copy, ValueEnum, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplayGainMode {
+    /// Writes ReplayGain tags, or R128 tags for opus.
+    Tags,
+    /// Applies the album gain to the audio itself, limited to prevent clipping.
+    Bake,
+}
+
+/// The measured loudness of a track, kept as gating blocks so that albums can be measured as a whole.
+#[derive(Debug, Clone, Default)]
+pub struct Loudness {
+    /// Mean square energy of every 400ms block, overlapping by 75%.
+    blocks: Vec<f64>,
+    /// The highest absolute sample value.
+    peak: f32,
+}
+
+/// Track and album gains in dB with their peaks, relative to the ReplayGain reference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gain {
+    pub track_gain: f64,
+    pub track_peak: f32,
+    pub album_gain: f64,
+    pub album_peak: f32,
+}
+
+impl Loudness {
+    /// Integrated loudness in LUFS, following the two-stage gating of EBU R128.
+    pub fn integrated(&self) -> Option<f64> {
+        integrated(&self.blocks)
+    }
+
+    /// Computes the gains of `track` within `album`, which should include `track` itself.
+    pub fn gain(track: &Loudness, album: &[&Loudness]) -> Option<Gain> {
+        let album_blocks = album.iter().flat_map(|x| x.blocks.iter().copied()).collect::<Vec<_>>();
+
+        Some(Gain {
+            track_gain: REPLAYGAIN_REFERENCE - track.integrated()?,
+            track_peak: track.peak,
+            album_gain: REPLAYGAIN_REFERENCE - integrated(&album_blocks)?,
+            album_peak: album.iter().map(|x| x.peak).fold(0.0, f32::max),
+        })
+    }
+}
+
+impl Gain {
+    /// Tags describing the gains, `R128_*_GAIN` in Q7.8 relative to -23 LUFS for opus, as players expect.
+    pub fn tags(&self, opus: bool) -> Vec<(&'static str, String)> {
+        if opus {
+            let r128 =
+                |gain: f64| (((gain - REPLAYGAIN_REFERENCE + R128_REFERENCE) * 256.0).round() as i32).to_string();
+            return vec![
+                ("R128_TRACK_GAIN", r128(self.track_gain)),
+                ("R128_ALBUM_GAIN", r128(self.album_gain)),
+            ];
+        }
+
+        vec![
+            ("REPLAYGAIN_TRACK_GAIN", format!("{:.2} dB", self.track_gain)),
+            ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", self.track_peak)),
+            ("REPLAYGAIN_ALBUM_GAIN", format!("{:.2} dB", self.album_gain)),
+            ("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", self.album_peak)),
+        ]
+    }
+
+    /// The album gain, lowered when it would push the album peak above full scale.
+    pub fn clipless_album_gain(&self) -> f64 {
+        if self.album_peak <= 0.0 {
+            return self.album_gain;
+        }
+
+        self.album_gain.min(-20.0 * (self.album_peak as f64).log10())
+    }
+}
+
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn integrated(blocks: &[f64]) -> Option<f64> {
+    let mean = |blocks: &mut dyn Iterator<Item = f64>| {
+        let (sum, count) = blocks.fold((0.0, 0), |(sum, count), x| (sum + x, count + 1));
+        (count > 0).then(|| sum / count as f64)
+    };
+
+    let absolute = blocks.iter().copied().filter(|x| loudness(*x) > -70.0);
+    let relative_gate = loudness(mean(&mut absolute.clone())?) - 10.0;
+    let gated = mean(&mut absolute.filter(|x| loudness(*x) > relative_gate))?;
+
+    Some(loudness(gated))
+}
+
+/// A second-order IIR filter in direct form I.
+#[derive(Debug, Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0]
+            - self.a[2] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The K-weighting filter of ITU-R BS.1770, a high shelf followed by a high pass, for any sample rate.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        ..Default::default()
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        ..Default::default()
+    };
+
+    [shelf, high_pass]
+}
+
+/// Measures interleaved samples in 100ms steps, combining 4 steps into each 400ms gating block.
+struct Meter {
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    step_frames: usize,
+    step_energy: f64,
+    step_position: usize,
+    steps: Vec<f64>,
+    peak: f32,
+    /// The range of frames to measure, frames outside of it are skipped.
+    range: (u64, Option<u64>),
+    position: u64,
+}
+
+impl Meter {
+    fn new(sample_rate: u32, channels: usize, range: (u64, Option<u64>)) -> Meter {
+        // Surround channels are weighted up and the LFE channel left out, assuming the usual 5.1 order.
+        let weights = (0..channels)
+            .map(|i| match (channels, i) {
+                (6, 3) => 0.0,
+                (6, 4 | 5) => 1.41,
+                _ => 1.0,
+            })
+            .collect();
+
+        Meter {
+            filters: vec![k_weighting(sample_rate); channels],
+            weights,
+            step_frames: (sample_rate as usize / 10).max(1),
+            step_energy: 0.0,
+            step_position: 0,
+            steps: vec![],
+            peak: 0.0,
+            range,
+            position: 0,
+        }
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        let channels = self.filters.len();
+        let count = (samples.len() / channels) as u64;
+        let start = self.position;
+        self.position += count;
+
+        // Only the frames within the range are measured.
+        let from = self.range.0.saturating_sub(start).min(count) as usize;
+        let to = self.range.1.map_or(count, |end| end.saturating_sub(start).min(count)) as usize;
+        if from >= to {
+            return;
+        }
+
+        for frame in samples[from * channels..to * channels].chunks_exact(channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                self.peak = self.peak.max(sample.abs());
+
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let filtered = high_pass.process(shelf.process(*sample as f64));
+                self.step_energy += self.weights[channel] * filtered * filtered;
+            }
+
+            self.step_position += 1;
+            if self.step_position == self.step_frames {
+                self.steps.push(self.step_energy / self.step_frames as f64);
+                self.step_energy = 0.0;
+                self.step_position = 0;
+            }
+        }
+    }
+
+    fn finish(self) -> Loudness {
+        let blocks = self.steps.windows(4).map(|x| x.iter().sum::<f64>() / 4.0).collect();
+
+        Loudness {
+            blocks,
+            peak: self.peak,
+        }
+    }
+}
+
+/// Decodes the `audio_track` of `path` and measures the loudness of each of the `ranges` (start and length in seconds,
+/// or `None` for the whole track).
+///
+/// The track is decoded once for all ranges, so the tracks of an album image are measured together.
+pub fn analyze(path: &Path, audio_track: usize, ranges: &[Option<(f64, Option<f64>)>]) -> Result<Vec<Loudness>> {
+    let path_str = path.to_string_lossy().to_string();
+    let file = File::open(path).map_err(|e| Error::from(e).with_context(path_str.clone()))?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|x| x.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let decode_error =
+        |e: SymphoniaError| Error::descriptive(format!("Failed to decode: {e}")).with_context(path_str.clone());
+    let mut format = symphonia::default::get_probe()
+        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
+        .map_err(decode_error)?
+        .format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .filter(|x| is_audio(x))
+        .nth(audio_track)
+        .ok_or_else(|| Error::descriptive("Audio track is not available").with_context(path_str.clone()))?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or_else(|| Error::descriptive("Sample rate is not available").with_context(path_str.clone()))?;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(decode_error)?;
+
+    let frames = |seconds: f64| (seconds * sample_rate as f64).round() as u64;
+    let frame_ranges = ranges
+        .iter()
+        .map(|range| match range {
+            Some((start, duration)) => (frames(*start), duration.map(|x| frames(start + x))),
+            None => (0, None),
+        })
+        .collect::<Vec<_>>();
+    // Decoding stops once every range is measured, unless one of them lasts until the end of the track.
+    let end = frame_ranges
+        .iter()
+        .map(|(_, end)| *end)
+        .try_fold(0, |max, end| end.map(|x| max.max(x)));
+
+    let mut meters: Option<Vec<Meter>> = None;
+    let mut buffer = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(decode_error(e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Corrupt packets are skipped, the same way players do.
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(decode_error(e)),
+        };
+
+        let spec = *decoded.spec();
+        let buffer = buffer.get_or_insert_with(|| SampleBuffer::<f32>::new(decoded.capacity() as u64, spec));
+        if buffer.capacity() < decoded.capacity() * spec.channels.count() {
+            *buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        }
+        buffer.copy_interleaved_ref(decoded);
+
+        let meters = meters.get_or_insert_with(|| {
+            frame_ranges
+                .iter()
+                .map(|range| Meter::new(spec.rate, spec.channels.count(), *range))
+                .collect()
+        });
+        for meter in meters.iter_mut() {
+            meter.push(buffer.samples());
+        }
+
+        if let Some(end) = end
+            && meters.first().is_some_and(|x| x.position >= end)
+        {
+            break;
+        }
+    }
+
+    let loudness = match meters {
+        Some(meters) => meters.into_iter().map(Meter::finish).collect(),
+        None => ranges.iter().map(|_| Loudness::default()).collect(),
+    };
+
+    Ok(loudness)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Loudness, Meter};
+    use std::f64::consts::PI;
+
+    fn sine(rate: u32, frequency: f64, amplitude: f64, seconds: f64) -> Loudness {
+        let mut meter = Meter::new(rate, 2, (0, None));
+        let samples = (0..(rate as f64 * seconds) as usize)
+            .flat_map(|i| {
+                let x = (amplitude * (2.0 * PI * frequency * i as f64 / rate as f64).sin()) as f32;
+                [x, x]
+            })
+            .collect::<Vec<_>>();
+
+        meter.push(&samples);
+        meter.finish()
+    }
+
+    #[test]
+    fn measures_reference_tones() {
+        // A stereo 997Hz sine at -20 dBFS per channel measures about -20 LUFS.
+        let tone = sine(48000, 997.0, 0.1, 5.0);
+        let integrated = tone.integrated().expect("tone should be measured");
+        assert!((integrated - -20.0).abs() < 0.1, "measured {integrated}");
+
+        // Half the amplitude is 6 LU down, both tracks averaging to about -22 LUFS for the album.
+        let softer = sine(44100, 997.0, 0.05, 5.0);
+        let gain = Loudness::gain(&tone, &[&tone, &softer]).expect("album should be measured");
+        assert!((gain.track_gain - 2.0).abs() < 0.1);
+        assert!((gain.album_gain - 4.04).abs() < 0.1, "album gain {}", gain.album_gain);
+        assert!((gain.album_peak - 0.1).abs() < 1e-3);
+        assert!((gain.clipless_album_gain() - 4.04).abs() < 0.1);
+
+        // Blocks 10 LU below the album are gated out, so a quiet interlude doesn't raise its gain.
+        let quiet = sine(48000, 997.0, 0.01, 5.0);
+        let gated = Loudness::gain(&tone, &[&tone, &quiet]).expect("album should be measured");
+        assert!((gated.album_gain - gated.track_gain).abs() < 1e-9);
+
+        let tags = gain.tags(true);
+        assert_eq!(tags[0], ("R128_TRACK_GAIN", "-768".to_string()));
+        assert!(Loudness::default().integrated().is_none());
+    }
+
+    #[test]
+    fn measures_only_the_range() {
+        // A loud second followed by a quiet one, pushed in chunks straddling the boundary.
+        let samples = (0..96000)
+            .flat_map(|i| {
+                let x = if i < 48000 { 0.5 } else { 0.1 } * (i as f32 * 0.13).sin();
+                [x, x]
+            })
+            .collect::<Vec<_>>();
+
+        let measure = |range| {
+            let mut meter = Meter::new(48000, 2, range);
+            for chunk in samples.chunks(2 * 1152) {
+                meter.push(chunk);
+            }
+            meter.finish()
+        };
+
+        let loud = measure((0, Some(48000)));
+        let quiet = measure((48000, None));
+        assert!((loud.peak - 0.5).abs() < 1e-3);
+        assert!((quiet.peak - 0.1).abs() < 1e-3);
+        assert_eq!(loud.blocks.len(), quiet.blocks.len());
+    }
+}
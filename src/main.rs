@@ -1,19 +1,39 @@
 use clap::{CommandFactory, Parser};
 use clap_complete::generate;
 
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, ConfigCommands};
+use config::Config;
 use errors::ErrorType;
 
+mod budget;
 mod cli;
 mod commands;
+mod config;
+mod cue;
 mod errors;
 mod format;
+mod loudness;
+mod playlist;
+mod query;
+mod rules;
 mod utils;
+mod watch;
 
 fn main() {
     let cli = Cli::parse();
     let run = match cli.command {
-        Commands::Sync(opts) => commands::sync::run(opts),
+        Commands::Sync(opts) => Config::load(cli.config.as_deref()).and_then(|x| commands::sync::run(*opts, &x)),
+        Commands::Status(opts) => Config::load(cli.config.as_deref()).and_then(|x| commands::status::run(*opts, &x)),
+        Commands::Prune(opts) => Config::load(cli.config.as_deref()).and_then(|x| commands::prune::run(*opts, &x)),
+        Commands::Pull(opts) => commands::pull::run(*opts),
+        Commands::Devices => commands::devices::run(),
+        Commands::Probe(opts) => Config::load(cli.config.as_deref()).and_then(|x| commands::probe::run(*opts, &x)),
+        Commands::Doctor(opts) => Config::load(cli.config.as_deref()).and_then(|x| commands::doctor::run(*opts, &x)),
+        Commands::Config { command } => match command {
+            ConfigCommands::Show(opts) => {
+                Config::load(cli.config.as_deref()).and_then(|x| commands::config::show(*opts, &x))
+            }
+        },
         Commands::Completion { shell } => {
             let mut cmd = Cli::command();
             generate(shell, &mut cmd, "tsync", &mut std::io::stdout());
@@ -25,5 +45,6 @@ fn main() {
         && e.type_ != ErrorType::Abort
     {
         eprintln!("{e}");
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,537 @@
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{Error, Result},
+    utils::path::normalize_path,
+};
+
+/// How playlists are synced with the target.
+#[derive(Debug, Clone, Copy, Default, ValueEnum, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlaylistMode {
+    /// The playlists of the source directory overwrite the ones on the target.
+    #[default]
+    Push,
+    /// Changes made on either side since the last sync are merged, and the merged playlist is written to both.
+    TwoWay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    pub const EXTENSIONS: [&'static str; 4] = ["m3u", "m3u8", "pls", "xspf"];
+
+    pub fn from_path(path: &Path) -> Option<PlaylistFormat> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        let format = match ext.as_str() {
+            "m3u" | "m3u8" => PlaylistFormat::M3u,
+            "pls" => PlaylistFormat::Pls,
+            "xspf" => PlaylistFormat::Xspf,
+            _ => return None,
+        };
+
+        Some(format)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaylistEntry {
+    /// The location exactly as written in the playlist, with `file://` URIs decoded.
+    pub location: String,
+    pub title: Option<String>,
+    /// Duration in seconds, `-1` or `None` when unknown.
+    pub duration: Option<i64>,
+}
+
+impl PlaylistEntry {
+    pub fn new(location: impl Into<String>) -> Self {
+        Self {
+            location: location.into(),
+            title: None,
+            duration: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    pub format: PlaylistFormat,
+    pub title: Option<String>,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+impl Playlist {
+    pub fn read(path: &Path) -> Result<Playlist> {
+        let format = PlaylistFormat::from_path(path)
+            .ok_or_else(|| Error::descriptive("Unsupported playlist format").with_context(path.to_string_lossy()))?;
+        let bytes = std::fs::read(path).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))?;
+        let contents = String::from_utf8_lossy(&bytes);
+        let contents = contents.trim_start_matches('\u{feff}');
+
+        match format {
+            PlaylistFormat::M3u => Ok(parse_m3u(contents)),
+            PlaylistFormat::Pls => Ok(parse_pls(contents)),
+            PlaylistFormat::Xspf => parse_xspf(contents).map_err(|e| e.with_context(path.to_string_lossy())),
+        }
+    }
+
+    pub fn serialize(&self) -> String {
+        match self.format {
+            PlaylistFormat::M3u => write_m3u(self),
+            PlaylistFormat::Pls => write_pls(self),
+            PlaylistFormat::Xspf => write_xspf(self),
+        }
+    }
+
+    /// Resolves an entry against the playlist file it was read from, returning `None` for non-file URIs.
+    pub fn resolve_entry(playlist_path: &Path, entry: &PlaylistEntry) -> Option<PathBuf> {
+        let location = entry.location.replace('\\', "/");
+        if location.contains("://") {
+            return None;
+        }
+
+        let path = Path::new(&location);
+        if path.is_absolute() {
+            return Some(normalize_path(path));
+        }
+
+        let parent = playlist_path.parent().unwrap_or(Path::new(""));
+        Some(normalize_path(&parent.join(path)))
+    }
+}
+
+/// A section of a three-way merge that both sides changed differently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict<T> {
+    pub ours: Vec<T>,
+    pub theirs: Vec<T>,
+}
+
+#[derive(Debug)]
+pub struct Merged<T> {
+    pub items: Vec<T>,
+    pub conflicts: Vec<Conflict<T>>,
+}
+
+/// Merges the changes `ours` and `theirs` made to `base`, the way `diff3` does.
+///
+/// Sections changed by one side only take that side, so additions, removals and moves merge cleanly unless both sides
+/// changed the same neighbouring entries. Such conflicts keep the entries of `ours` followed by the ones only `theirs`
+/// has, so that nothing added on either side is lost.
+pub fn merge<T: Clone + PartialEq>(base: &[T], ours: &[T], theirs: &[T]) -> Merged<T> {
+    let in_ours = common_subsequence(base, ours);
+    let in_theirs = common_subsequence(base, theirs);
+
+    let mut items = Vec::new();
+    let mut conflicts = Vec::new();
+    let (mut o, mut a, mut b) = (0, 0, 0);
+
+    loop {
+        while o < base.len() && in_ours[o] == Some(a) && in_theirs[o] == Some(b) {
+            items.push(base[o].clone());
+            (o, a, b) = (o + 1, a + 1, b + 1);
+        }
+
+        // The changed section ends at the next entry of `base` both sides kept.
+        let next = (o..base.len()).find_map(|i| Some((i, in_ours[i]?, in_theirs[i]?)));
+        let (o_end, a_end, b_end) = next.unwrap_or((base.len(), ours.len(), theirs.len()));
+        let (base_part, ours_part, theirs_part) = (&base[o..o_end], &ours[a..a_end], &theirs[b..b_end]);
+
+        if ours_part == base_part {
+            items.extend_from_slice(theirs_part);
+        } else if theirs_part == base_part || theirs_part == ours_part {
+            items.extend_from_slice(ours_part);
+        } else {
+            items.extend_from_slice(ours_part);
+            items.extend(theirs_part.iter().filter(|x| !ours_part.contains(x)).cloned());
+            conflicts.push(Conflict {
+                ours: ours_part.to_vec(),
+                theirs: theirs_part.to_vec(),
+            });
+        }
+
+        if next.is_none() {
+            break;
+        }
+        (o, a, b) = (o_end, a_end, b_end);
+    }
+
+    Merged { items, conflicts }
+}
+
+/// For each item of `base`, the index of the item of `other` matching it in a longest common subsequence.
+fn common_subsequence<T: PartialEq>(base: &[T], other: &[T]) -> Vec<Option<usize>> {
+    let (n, m) = (base.len(), other.len());
+
+    // `lengths[i][j]` is the length of the longest common subsequence of `base[i..]` and `other[j..]`.
+    let mut lengths = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lengths[i][j] = if base[i] == other[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut matches = vec![None; n];
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if base[i] == other[j] {
+            matches[i] = Some(j);
+            (i, j) = (i + 1, j + 1);
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    matches
+}
+
+fn decode_location(location: &str) -> String {
+    match location.strip_prefix("file://") {
+        Some(rest) => percent_decode(rest.strip_prefix("localhost").unwrap_or(rest)),
+        None => location.to_string(),
+    }
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(hex) = bytes.get(i + 1..i + 3)
+            && let Some(byte) = std::str::from_utf8(hex)
+                .ok()
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+        {
+            decoded.push(byte);
+            i += 3;
+            continue;
+        }
+
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+fn parse_m3u(contents: &str) -> Playlist {
+    let mut entries = Vec::new();
+    let mut title = None;
+    let mut pending: Option<(Option<i64>, Option<String>)> = None;
+
+    for line in contents.lines().map(str::trim).filter(|x| !x.is_empty()) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            let (duration, name) = info.split_once(',').unwrap_or((info, ""));
+            // Attributes like `tvg-id="..."` may follow the duration, only the leading number matters.
+            let duration = duration.split_whitespace().next().and_then(|x| x.parse().ok());
+            let name = Some(name.trim().to_string()).filter(|x| !x.is_empty());
+            pending = Some((duration, name));
+        } else if let Some(name) = line.strip_prefix("#PLAYLIST:") {
+            title = Some(name.trim().to_string());
+        } else if !line.starts_with('#') {
+            let (duration, title) = pending.take().unwrap_or((None, None));
+            entries.push(PlaylistEntry {
+                location: decode_location(line),
+                title,
+                duration,
+            });
+        }
+    }
+
+    Playlist {
+        format: PlaylistFormat::M3u,
+        title,
+        entries,
+    }
+}
+
+fn write_m3u(playlist: &Playlist) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    if let Some(title) = &playlist.title {
+        out.push_str(&format!("#PLAYLIST:{title}\n"));
+    }
+
+    for entry in &playlist.entries {
+        if entry.title.is_some() || entry.duration.is_some() {
+            let duration = entry.duration.unwrap_or(-1);
+            let title = entry.title.as_deref().unwrap_or_default();
+            out.push_str(&format!("#EXTINF:{duration},{title}\n"));
+        }
+
+        out.push_str(&entry.location);
+        out.push('\n');
+    }
+
+    out
+}
+
+fn pls_entry_at(entries: &mut Vec<(u32, PlaylistEntry)>, index: u32) -> &mut PlaylistEntry {
+    let position = match entries.iter().position(|(i, _)| *i == index) {
+        Some(position) => position,
+        None => {
+            entries.push((index, PlaylistEntry::new("")));
+            entries.len() - 1
+        }
+    };
+
+    &mut entries[position].1
+}
+
+fn parse_pls(contents: &str) -> Playlist {
+    let mut entries = Vec::<(u32, PlaylistEntry)>::new();
+    for line in contents.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+
+        let key = key.trim().to_lowercase();
+        let value = value.trim();
+
+        let (field, index) = match key.find(|c: char| c.is_ascii_digit()) {
+            Some(at) => (&key[..at], key[at..].parse::<u32>().ok()),
+            None => continue,
+        };
+        let Some(index) = index else {
+            continue;
+        };
+
+        match field {
+            "file" => pls_entry_at(&mut entries, index).location = decode_location(value),
+            "title" => pls_entry_at(&mut entries, index).title = Some(value.to_string()),
+            "length" => pls_entry_at(&mut entries, index).duration = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    entries.sort_by_key(|(index, _)| *index);
+
+    Playlist {
+        format: PlaylistFormat::Pls,
+        title: None,
+        entries: entries
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .filter(|x| !x.location.is_empty())
+            .collect(),
+    }
+}
+
+fn write_pls(playlist: &Playlist) -> String {
+    let mut out = String::from("[playlist]\n");
+    for (i, entry) in playlist.entries.iter().enumerate() {
+        let n = i + 1;
+        out.push_str(&format!("File{n}={}\n", entry.location));
+        if let Some(title) = &entry.title {
+            out.push_str(&format!("Title{n}={title}\n"));
+        }
+        if let Some(duration) = entry.duration {
+            out.push_str(&format!("Length{n}={duration}\n"));
+        }
+    }
+
+    out.push_str(&format!("NumberOfEntries={}\nVersion=2\n", playlist.entries.len()));
+    out
+}
+
+fn xml_unescape(input: &str) -> String {
+    input
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn xml_escape(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Returns the text of the first `<tag>` element within `xml`.
+fn xml_element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&close)? + start;
+
+    Some(&xml[start..end])
+}
+
+fn parse_xspf(contents: &str) -> Result<Playlist> {
+    let track_list = xml_element(contents, "trackList")
+        .ok_or_else(|| Error::descriptive("XSPF playlist has no trackList element"))?;
+    let title = contents
+        .split("<trackList>")
+        .next()
+        .and_then(|x| xml_element(x, "title"))
+        .map(xml_unescape);
+
+    let mut entries = Vec::new();
+    for track in track_list.split("<track>").skip(1) {
+        let Some(location) = xml_element(track, "location") else {
+            continue;
+        };
+
+        let location = xml_unescape(location.trim());
+        let location = if location.contains("://") {
+            decode_location(&location)
+        } else {
+            percent_decode(&location)
+        };
+
+        entries.push(PlaylistEntry {
+            location,
+            title: xml_element(track, "title").map(xml_unescape),
+            duration: xml_element(track, "duration")
+                .and_then(|x| x.trim().parse::<i64>().ok())
+                .map(|ms| ms / 1000),
+        });
+    }
+
+    Ok(Playlist {
+        format: PlaylistFormat::Xspf,
+        title,
+        entries,
+    })
+}
+
+fn write_xspf(playlist: &Playlist) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    if let Some(title) = &playlist.title {
+        out.push_str(&format!("  <title>{}</title>\n", xml_escape(title)));
+    }
+
+    out.push_str("  <trackList>\n");
+    for entry in &playlist.entries {
+        out.push_str("    <track>\n");
+        out.push_str(&format!(
+            "      <location>{}</location>\n",
+            xml_escape(&percent_encode(&entry.location))
+        ));
+        if let Some(title) = &entry.title {
+            out.push_str(&format!("      <title>{}</title>\n", xml_escape(title)));
+        }
+        if let Some(duration) = entry.duration.filter(|x| *x >= 0) {
+            out.push_str(&format!("      <duration>{}</duration>\n", duration * 1000));
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Conflict, Playlist, PlaylistEntry, PlaylistFormat, merge, parse_m3u, parse_pls, parse_xspf};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn parse_m3u_reads_extinf_and_file_uris() {
+        let playlist = parse_m3u(
+            "#EXTM3U\n#EXTINF:245,Porter Robinson - Knock Yourself Out XD\nPorter Robinson/SMILE! :D/01.flac\n\
+             file:///music/Some%20Album/02.flac\n",
+        );
+
+        assert_eq!(playlist.entries.len(), 2);
+        assert_eq!(playlist.entries[0].duration, Some(245));
+        assert_eq!(
+            playlist.entries[0].title.as_deref(),
+            Some("Porter Robinson - Knock Yourself Out XD")
+        );
+        assert_eq!(playlist.entries[1].location, "/music/Some Album/02.flac");
+        assert_eq!(playlist.entries[1].title, None);
+    }
+
+    #[test]
+    fn parse_pls_orders_by_index() {
+        let playlist = parse_pls("[playlist]\nFile2=b.flac\nFile1=a.flac\nTitle1=A\nNumberOfEntries=2\n");
+
+        let locations = playlist.entries.iter().map(|x| x.location.as_str()).collect::<Vec<_>>();
+        assert_eq!(locations, vec!["a.flac", "b.flac"]);
+        assert_eq!(playlist.entries[0].title.as_deref(), Some("A"));
+    }
+
+    #[test]
+    fn xspf_round_trips() {
+        let playlist = Playlist {
+            format: PlaylistFormat::Xspf,
+            title: Some("Mix & Match".to_string()),
+            entries: vec![PlaylistEntry {
+                location: "Artist/Album/01 Track #1.opus".to_string(),
+                title: Some("Track <1>".to_string()),
+                duration: Some(200),
+            }],
+        };
+
+        let parsed = parse_xspf(&playlist.serialize()).expect("xspf should parse");
+        assert_eq!(parsed.title, playlist.title);
+        assert_eq!(parsed.entries, playlist.entries);
+    }
+
+    #[test]
+    fn resolve_entry_handles_relative_paths() {
+        let entry = PlaylistEntry::new("..\\Artist\\Album\\01.flac");
+        let resolved = Playlist::resolve_entry(Path::new("/music/Playlists/mix.m3u8"), &entry);
+
+        assert_eq!(resolved, Some(PathBuf::from("/music/Artist/Album/01.flac")));
+    }
+
+    #[test]
+    fn merge_combines_changes_from_both_sides() {
+        let base = ["a", "b", "c", "d", "e", "f"];
+
+        // One side removes `b` and adds `x`, the other moves `e` to the front and adds `y` at the end.
+        let merged = merge(
+            &base,
+            &["a", "c", "x", "d", "e", "f"],
+            &["e", "a", "b", "c", "d", "f", "y"],
+        );
+        assert_eq!(merged.items, ["e", "a", "c", "x", "d", "f", "y"]);
+        assert!(merged.conflicts.is_empty());
+
+        // Both sides replace `c` differently.
+        let merged = merge(&base, &["a", "b", "x", "d", "e", "f"], &["a", "b", "y", "d", "e", "f"]);
+        assert_eq!(merged.items, ["a", "b", "x", "y", "d", "e", "f"]);
+        assert_eq!(
+            merged.conflicts,
+            [Conflict {
+                ours: vec!["x"],
+                theirs: vec!["y"]
+            }]
+        );
+    }
+}
@@ -0,0 +1,443 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{Error, Result},
+    format::TrackData,
+    utils::glob,
+};
+
+/// A boolean expression over track tags, e.g. `genre = Jazz AND year >= 1960`.
+///
+/// - Comparisons are written as `<field> <op> <value>`, where op is one of `=`, `!=`, `<`, `<=`, `>`, `>=`.
+/// - Values can be bare words (`albumartist = Porter Robinson`) or quoted (`album = "SMILE! :D"`).
+/// - `=` and `!=` are case-insensitive and support glob patterns (`album = "Stream Palette *"`).
+/// - Ordering operators compare numerically when both sides start with a number, e.g. `year` or `tracknumber`.
+/// - Expressions combine with `AND`, `OR`, `NOT` and parentheses, `AND` binding tighter than `OR`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Query {
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Compare { field: String, op: Op, value: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Query {
+    pub fn matches(&self, track: &TrackData) -> bool {
+        match self {
+            Query::And(a, b) => a.matches(track) && b.matches(track),
+            Query::Or(a, b) => a.matches(track) || b.matches(track),
+            Query::Not(a) => !a.matches(track),
+            Query::Compare { field, op, value } => {
+                let values = track.tag(field).unwrap_or_default();
+                match op {
+                    Op::Eq => values.iter().any(|x| equals(x, value)),
+                    Op::Ne => !values.iter().any(|x| equals(x, value)),
+                    _ => values.iter().any(|x| {
+                        let ordering = compare(x, value);
+                        match op {
+                            Op::Lt => ordering == Ordering::Less,
+                            Op::Le => ordering != Ordering::Greater,
+                            Op::Gt => ordering == Ordering::Greater,
+                            Op::Ge => ordering != Ordering::Less,
+                            Op::Eq | Op::Ne => unreachable!(),
+                        }
+                    }),
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Query {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, position: 0 };
+        let query = parser.parse_or()?;
+
+        if let Some(token) = parser.tokens.get(parser.position) {
+            let message = format!("Unexpected {token} in query");
+            return Err(Error::descriptive(message).with_context(s));
+        }
+
+        Ok(query)
+    }
+}
+
+impl TryFrom<String> for Query {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Query> for String {
+    fn from(value: Query) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Query::And(a, b) => write!(f, "({a} AND {b})"),
+            Query::Or(a, b) => write!(f, "({a} OR {b})"),
+            Query::Not(a) => write!(f, "NOT {a}"),
+            Query::Compare { field, op, value } => {
+                let quote = if value.contains('"') { '\'' } else { '"' };
+                write!(f, "{field} {op} {quote}{value}{quote}")
+            }
+        }
+    }
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        };
+        write!(f, "{op}")
+    }
+}
+
+fn equals(value: &str, pattern: &str) -> bool {
+    let value = value.to_lowercase();
+    let pattern = pattern.to_lowercase();
+
+    if glob::is_glob(&pattern) {
+        glob::matches(&pattern, &value)
+    } else {
+        value == pattern
+    }
+}
+
+/// Parses the leading number of a value, e.g. `1965` from `1965-03-02` or `3` from `3/12`.
+fn leading_number(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let end = value
+        .char_indices()
+        .find(|(i, c)| !(c.is_ascii_digit() || *c == '.' || (*i == 0 && *c == '-')))
+        .map(|(i, _)| i)
+        .unwrap_or(value.len());
+
+    value[..end].parse().ok()
+}
+
+fn compare(value: &str, other: &str) -> Ordering {
+    match (leading_number(value), leading_number(other)) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => value.to_lowercase().cmp(&other.to_lowercase()),
+    }
+}
+
+/// Groups tracks by album folder and returns the tracks of every album with at least one matching track.
+///
+/// When `partial` is set, only the matching tracks are returned instead.
+pub fn select_tracks<'a, I>(tracks: I, query: &Query, partial: bool) -> HashSet<PathBuf>
+where
+    I: IntoIterator<Item = (&'a Path, &'a TrackData)>,
+{
+    let mut albums = HashMap::<&Path, (bool, Vec<&Path>)>::new();
+    let mut selected = HashSet::new();
+
+    for (path, track) in tracks {
+        let is_match = query.matches(track);
+
+        if partial {
+            if is_match {
+                selected.insert(path.to_path_buf());
+            }
+            continue;
+        }
+
+        let album = path.parent().unwrap_or(path);
+        let (album_matches, album_tracks) = albums.entry(album).or_default();
+        *album_matches |= is_match;
+        album_tracks.push(path);
+    }
+
+    for (is_match, album_tracks) in albums.into_values() {
+        if is_match {
+            selected.extend(album_tracks.into_iter().map(Path::to_path_buf));
+        }
+    }
+
+    selected
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Op(Op),
+    Word(String),
+    Quoted(String),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::Op(op) => write!(f, "operator '{op}'"),
+            Token::Word(word) => write!(f, "'{word}'"),
+            Token::Quoted(word) => write!(f, "\"{word}\""),
+        }
+    }
+}
+
+impl Token {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word(word) if word.eq_ignore_ascii_case(keyword))
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let chars = input.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '=' => {
+                tokens.push(Token::Op(Op::Eq));
+                i += 1;
+            }
+            '!' if next == Some('=') => {
+                tokens.push(Token::Op(Op::Ne));
+                i += 2;
+            }
+            '<' | '>' => {
+                let op = match (c, next == Some('=')) {
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    _ => Op::Ge,
+                };
+                tokens.push(Token::Op(op));
+                i += if next == Some('=') { 2 } else { 1 };
+            }
+            '"' | '\'' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|x| *x == c)
+                    .ok_or_else(|| Error::descriptive("Unterminated string in query").with_context(input))?;
+                tokens.push(Token::Quoted(chars[i + 1..i + 1 + end].iter().collect()));
+                i += end + 2;
+            }
+            _ => {
+                let start = i;
+                while i < chars.len() {
+                    let c = chars[i];
+                    let is_ne = c == '!' && chars.get(i + 1) == Some(&'=');
+                    if c.is_whitespace() || is_ne || matches!(c, '(' | ')' | '=' | '<' | '>' | '"') {
+                        break;
+                    }
+                    i += 1;
+                }
+                tokens.push(Token::Word(chars[start..i].iter().collect()));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Query> {
+        let mut query = self.parse_and()?;
+        while self.peek().is_some_and(|x| x.is_keyword("or")) {
+            self.position += 1;
+            query = Query::Or(Box::new(query), Box::new(self.parse_and()?));
+        }
+
+        Ok(query)
+    }
+
+    fn parse_and(&mut self) -> Result<Query> {
+        let mut query = self.parse_not()?;
+        while self.peek().is_some_and(|x| x.is_keyword("and")) {
+            self.position += 1;
+            query = Query::And(Box::new(query), Box::new(self.parse_not()?));
+        }
+
+        Ok(query)
+    }
+
+    fn parse_not(&mut self) -> Result<Query> {
+        if self.peek().is_some_and(|x| x.is_keyword("not")) {
+            self.position += 1;
+            return Ok(Query::Not(Box::new(self.parse_not()?)));
+        }
+
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Query> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let query = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(query),
+                    _ => Err(Error::descriptive("Expected ')' in query")),
+                }
+            }
+            Some(Token::Word(field)) => {
+                let op = match self.next() {
+                    Some(Token::Op(op)) => op,
+                    Some(token) => return Err(Error::descriptive(format!("Expected an operator, found {token}"))),
+                    None => return Err(Error::descriptive(format!("Expected an operator after '{field}'"))),
+                };
+
+                Ok(Query::Compare {
+                    field: field.to_lowercase(),
+                    op,
+                    value: self.parse_value(&field)?,
+                })
+            }
+            Some(token) => Err(Error::descriptive(format!("Expected a field name, found {token}"))),
+            None => Err(Error::descriptive("Unexpected end of query")),
+        }
+    }
+
+    /// Reads a quoted value, or consecutive bare words up to the next keyword or parenthesis.
+    fn parse_value(&mut self, field: &str) -> Result<String> {
+        if let Some(Token::Quoted(value)) = self.peek() {
+            let value = value.clone();
+            self.position += 1;
+            return Ok(value);
+        }
+
+        let mut words = Vec::new();
+        while let Some(Token::Word(word)) = self.peek() {
+            if ["and", "or", "not"].iter().any(|x| word.eq_ignore_ascii_case(x)) {
+                break;
+            }
+
+            words.push(word.clone());
+            self.position += 1;
+        }
+
+        if words.is_empty() {
+            return Err(Error::descriptive(format!("Expected a value for '{field}'")));
+        }
+
+        Ok(words.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Op, Query};
+    use crate::format::{SourceFormat, TrackData};
+    use std::collections::HashMap;
+
+    fn track(tags: &[(&str, &str)]) -> TrackData {
+        let mut map = HashMap::<String, Vec<String>>::new();
+        for (key, value) in tags {
+            map.entry(key.to_string()).or_default().push(value.to_string());
+        }
+
+        TrackData {
+            tags: map,
+            ..TrackData::from_format(SourceFormat::Flac)
+        }
+    }
+
+    #[test]
+    fn parses_bare_multi_word_values() {
+        let query = "albumartist = Porter Robinson"
+            .parse::<Query>()
+            .expect("query should parse");
+
+        assert_eq!(
+            query,
+            Query::Compare {
+                field: "albumartist".to_string(),
+                op: Op::Eq,
+                value: "Porter Robinson".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn evaluates_boolean_expressions() {
+        let query = "(genre = jazz OR genre = \"Bossa *\") AND year >= 1960 AND NOT album = *Live*"
+            .parse::<Query>()
+            .expect("query should parse");
+
+        assert!(query.matches(&track(&[
+            ("genre", "Jazz"),
+            ("date", "1965-03-02"),
+            ("album", "Takin' Off")
+        ])));
+        assert!(query.matches(&track(&[("genre", "Bossa Nova"), ("date", "1964")])));
+        assert!(!query.matches(&track(&[("genre", "Jazz"), ("date", "1959")])));
+        assert!(!query.matches(&track(&[
+            ("genre", "Jazz"),
+            ("date", "1970"),
+            ("album", "Live at Newport")
+        ])));
+        assert!(!query.matches(&track(&[("genre", "Rock"), ("date", "1970")])));
+    }
+
+    #[test]
+    fn rejects_incomplete_queries() {
+        assert!("genre =".parse::<Query>().is_err());
+        assert!("(genre = Jazz".parse::<Query>().is_err());
+        assert!("genre Jazz".parse::<Query>().is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{Error, Result},
+    format::{
+        Codec, SourceFormat, TrackData,
+        encoder::{BitrateMode, Encoder, LosslessLimits, Resample},
+    },
+    query::Query,
+    utils::glob,
+};
+
+/// A transcode rule, mapping the tracks matching every one of its conditions to an action.
+///
+/// ```toml
+/// [[rules]]
+/// path = "Hi-Res/**"
+/// action = "passthrough"
+///
+/// [[rules]]
+/// query = "genre = Classical"
+/// action = "transcode"
+/// codec = "opus"
+/// bitrate = 192
+///
+/// [[rules]]
+/// source-codecs = ["mp3"]
+/// min-bitrate = 257
+/// action = "transcode"
+/// codec = "mp3"
+/// bitrate = 160
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Rule {
+    /// A glob matched against the path relative to the source directory, or any of its parent folders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_codecs: Vec<SourceFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<Query>,
+    /// Source bitrate bounds in kbps, inclusive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_bitrate: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bitrate: Option<u32>,
+    /// Source sample rate bounds in Hz, inclusive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_sample_rate: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sample_rate: Option<u32>,
+
+    pub action: RuleAction,
+    /// The codec to transcode into, defaults to the `codec` of the sync.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<Codec>,
+    /// The bitrate to transcode with. Without `bitrate`, `quality` and `mode`, the encoder settings of the sync are
+    /// used when the codecs match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<BitrateMode>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleAction {
+    Passthrough,
+    Transcode,
+    Skip,
+}
+
+/// What happens to a single track during a sync.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackAction {
+    /// The track is copied over as is.
+    Passthrough,
+    Transcode(Encoder),
+    Skip,
+}
+
+impl Rule {
+    pub fn matches(&self, rel_path: &Path, track: &TrackData) -> bool {
+        if let Some(pattern) = &self.path {
+            let matches_path = rel_path
+                .ancestors()
+                .filter(|x| !x.as_os_str().is_empty())
+                .any(|x| glob::matches_path(pattern, &x.to_string_lossy().replace('\\', "/")));
+
+            if !matches_path {
+                return false;
+            }
+        }
+
+        if !self.source_codecs.is_empty() && !self.source_codecs.contains(&track.format) {
+            return false;
+        }
+
+        if self.query.as_ref().is_some_and(|x| !x.matches(track)) {
+            return false;
+        }
+
+        // Tracks missing a property never satisfy a bound on it.
+        let within = |value: Option<u32>, min: Option<u32>, max: Option<u32>| {
+            if min.is_none() && max.is_none() {
+                return true;
+            }
+
+            value.is_some_and(|x| min.is_none_or(|min| x >= min) && max.is_none_or(|max| x <= max))
+        };
+
+        within(track.bitrate, self.min_bitrate, self.max_bitrate)
+            && within(track.sample_rate, self.min_sample_rate, self.max_sample_rate)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct RulesFile {
+    rules: Vec<Rule>,
+}
+
+/// Reads the `[[rules]]` tables of a TOML file.
+pub fn read_rules(path: &Path) -> Result<Vec<Rule>> {
+    let contents = std::fs::read_to_string(path).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))?;
+    let file =
+        toml::from_str::<RulesFile>(&contents).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))?;
+
+    Ok(file.rules)
+}
+
+/// Decides the action of each track, checking the rules in order before falling back to the codec lists.
+///
+/// Lossless tracks exceeding the [`LosslessLimits`] are transcoded into their own codec instead of passed through.
+#[derive(Debug)]
+pub struct Policy {
+    rules: Vec<(Rule, TrackAction)>,
+    transcode: Option<Encoder>,
+    limits: LosslessLimits,
+    transcode_codecs: Vec<SourceFormat>,
+    sync_codecs: Vec<SourceFormat>,
+}
+
+impl Policy {
+    pub fn new(
+        rules: Vec<Rule>,
+        transcode: Option<Encoder>,
+        limits: LosslessLimits,
+        transcode_codecs: Vec<SourceFormat>,
+        sync_codecs: Vec<SourceFormat>,
+    ) -> Result<Policy> {
+        let rules = rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let action = match rule.action {
+                    RuleAction::Passthrough => TrackAction::Passthrough,
+                    RuleAction::Skip => TrackAction::Skip,
+                    RuleAction::Transcode => {
+                        let context = format!("rule {}", i + 1);
+                        let codec = rule.codec.or(transcode.map(|x| x.codec())).ok_or_else(|| {
+                            Error::descriptive("Transcode rules need a codec when the sync has none")
+                                .with_context(context.clone())
+                        })?;
+                        let has_settings = rule.bitrate.is_some() || rule.quality.is_some() || rule.mode.is_some();
+                        let encoder = match transcode {
+                            Some(encoder) if !has_settings && encoder.codec() == codec => encoder,
+                            _ => Encoder::new(codec, rule.bitrate, rule.quality, rule.mode)
+                                .map_err(|e| e.with_context(context))?,
+                        };
+
+                        TrackAction::Transcode(encoder)
+                    }
+                };
+
+                Ok((rule, action))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Policy {
+            rules,
+            transcode,
+            limits,
+            transcode_codecs,
+            sync_codecs,
+        })
+    }
+
+    /// The encoder tracks matching `transcode_codecs` are transcoded with.
+    pub fn transcode(&self) -> Option<&Encoder> {
+        self.transcode.as_ref()
+    }
+
+    pub fn has_rules(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// The formats of the tracks that might not be skipped, used to narrow down the files to read.
+    pub fn readable_formats(&self) -> Vec<SourceFormat> {
+        if self.has_rules() {
+            return SourceFormat::value_variants().to_vec();
+        }
+
+        let mut codecs = self.sync_codecs.clone();
+        if self.transcode.is_some() {
+            codecs.extend(&self.transcode_codecs);
+        }
+
+        codecs
+    }
+
+    pub fn action(&self, rel_path: &Path, track: &TrackData) -> TrackAction {
+        let action = self.matching_action(rel_path, track);
+        let codec = match track.format.codec() {
+            Some(codec) if action == TrackAction::Passthrough && codec.is_lossless() => codec,
+            _ => return action,
+        };
+        if self.limits.resample(track).is_none() {
+            return action;
+        }
+
+        match self.transcode {
+            Some(encoder) if encoder.codec() == codec => TrackAction::Transcode(encoder),
+            _ => Encoder::new(codec, None, None, None)
+                .map(TrackAction::Transcode)
+                .unwrap_or(action),
+        }
+    }
+
+    /// Why [`Policy::action`] decides what it does for `track`, e.g. `rule 2` or `sync codecs`.
+    pub fn reason(&self, rel_path: &Path, track: &TrackData) -> String {
+        let action = self.matching_action(rel_path, track);
+        if action != self.action(rel_path, track) {
+            return "above the lossless limits".to_string();
+        }
+
+        if let Some(i) = self.rules.iter().position(|(rule, _)| rule.matches(rel_path, track)) {
+            return format!("rule {}", i + 1);
+        }
+
+        match action {
+            TrackAction::Passthrough => "sync codecs".to_string(),
+            TrackAction::Transcode(_) => "transcode codecs".to_string(),
+            TrackAction::Skip => "no codec or rule".to_string(),
+        }
+    }
+
+    /// The conversion applied when transcoding `track` with `encoder`, only lossless outputs are resampled.
+    pub fn resample(&self, encoder: &Encoder, track: &TrackData) -> Option<Resample> {
+        encoder
+            .codec()
+            .is_lossless()
+            .then(|| self.limits.resample(track))
+            .flatten()
+    }
+
+    fn matching_action(&self, rel_path: &Path, track: &TrackData) -> TrackAction {
+        if let Some((_, action)) = self.rules.iter().find(|(rule, _)| rule.matches(rel_path, track)) {
+            return *action;
+        }
+
+        if self.sync_codecs.contains(&track.format) {
+            return TrackAction::Passthrough;
+        }
+
+        match self.transcode {
+            Some(encoder) if self.transcode_codecs.contains(&track.format) => TrackAction::Transcode(encoder),
+            _ => TrackAction::Skip,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Policy, TrackAction};
+    use crate::format::{
+        Codec, SourceFormat, TrackData,
+        encoder::{Encoder, LosslessLimits},
+    };
+    use std::{collections::HashMap, path::Path};
+
+    fn track(format: SourceFormat, bitrate: u32, genre: &str) -> TrackData {
+        TrackData {
+            tags: HashMap::from([("genre".to_string(), vec![genre.to_string()])]),
+            sample_rate: Some(44100),
+            bit_depth: Some(16),
+            duration: Some(180.0),
+            bitrate: Some(bitrate),
+            ..TrackData::from_format(format)
+        }
+    }
+
+    fn encoder(codec: Codec, bitrate: u32) -> Encoder {
+        Encoder::new(codec, Some(bitrate), None, None).expect("encoder should be valid")
+    }
+
+    #[test]
+    fn applies_first_matching_rule() {
+        let rules = toml::from_str::<super::RulesFile>(
+            r#"
+            [[rules]]
+            path = "Hi-Res"
+            action = "passthrough"
+
+            [[rules]]
+            query = "genre = Classical"
+            action = "transcode"
+            bitrate = 192
+
+            [[rules]]
+            source-codecs = ["mp3"]
+            min-bitrate = 257
+            action = "transcode"
+            codec = "mp3"
+            bitrate = 160
+            "#,
+        )
+        .expect("rules should parse")
+        .rules;
+
+        let policy = Policy::new(
+            rules,
+            Encoder::new(Codec::Opus, Some(128), None, None).ok(),
+            Default::default(),
+            vec![SourceFormat::Flac, SourceFormat::Alac],
+            vec![SourceFormat::Opus, SourceFormat::Mp3],
+        )
+        .expect("policy should build");
+
+        let action = |path: &str, track: &TrackData| policy.action(Path::new(path), track);
+
+        assert_eq!(
+            action("Hi-Res/A/01.flac", &track(SourceFormat::Flac, 2300, "Classical")),
+            TrackAction::Passthrough
+        );
+        assert_eq!(
+            action("B/01.flac", &track(SourceFormat::Flac, 900, "Classical")),
+            TrackAction::Transcode(encoder(Codec::Opus, 192))
+        );
+        assert_eq!(
+            action("B/01.flac", &track(SourceFormat::Flac, 900, "Jazz")),
+            TrackAction::Transcode(encoder(Codec::Opus, 128))
+        );
+        assert_eq!(
+            action("C/01.mp3", &track(SourceFormat::Mp3, 320, "Jazz")),
+            TrackAction::Transcode(encoder(Codec::Mp3, 160))
+        );
+        assert_eq!(
+            action("C/02.mp3", &track(SourceFormat::Mp3, 192, "Jazz")),
+            TrackAction::Passthrough
+        );
+        assert_eq!(
+            action("D/01.m4a", &track(SourceFormat::AacLc, 256, "Jazz")),
+            TrackAction::Skip
+        );
+
+        let reason = |path: &str, track: &TrackData| policy.reason(Path::new(path), track);
+        assert_eq!(
+            reason("B/01.flac", &track(SourceFormat::Flac, 900, "Classical")),
+            "rule 2"
+        );
+        assert_eq!(
+            reason("B/01.flac", &track(SourceFormat::Flac, 900, "Jazz")),
+            "transcode codecs"
+        );
+        assert_eq!(
+            reason("C/02.mp3", &track(SourceFormat::Mp3, 192, "Jazz")),
+            "sync codecs"
+        );
+        assert_eq!(
+            reason("D/01.m4a", &track(SourceFormat::AacLc, 256, "Jazz")),
+            "no codec or rule"
+        );
+    }
+
+    #[test]
+    fn transcodes_lossless_tracks_exceeding_limits() {
+        let limits = LosslessLimits::new(None, Some(16)).expect("limits should be valid");
+        let policy = Policy::new(vec![], None, limits, vec![], vec![SourceFormat::Flac]).expect("policy should build");
+
+        let mut hi_res = track(SourceFormat::Flac, 2300, "Jazz");
+        hi_res.bit_depth = Some(24);
+
+        let action = policy.action(Path::new("A/01.flac"), &hi_res);
+        let TrackAction::Transcode(encoder) = action else {
+            panic!("hi-res track should be transcoded, got {action:?}");
+        };
+
+        assert_eq!(encoder.codec(), Codec::Flac);
+        assert!(policy.resample(&encoder, &hi_res).is_some());
+        assert_eq!(
+            policy.reason(Path::new("A/01.flac"), &hi_res),
+            "above the lossless limits"
+        );
+        assert_eq!(
+            policy.action(Path::new("A/02.flac"), &track(SourceFormat::Flac, 900, "Jazz")),
+            TrackAction::Passthrough
+        );
+    }
+}
@@ -3,31 +3,221 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use crate::errors::Result;
+use crate::{
+    errors::{Error, Result},
+    playlist::Playlist,
+    query::Query,
+    utils::path::{expand_home, normalize_path},
+};
 
 pub mod ffmpeg;
 pub mod fs;
+pub mod glob;
+pub mod ignore;
 pub mod path;
 
-pub fn parse_sync_list(source_dir: &Path, path: &Path) -> Result<HashSet<PathBuf>> {
-    let contents = std::fs::read_to_string(path)?;
+#[derive(Debug, Default)]
+pub struct SyncList {
+    /// Folders or files to sync, joined onto the source directory, in the order they were listed.
+    pub paths: Vec<PathBuf>,
+    /// Patterns relative to the source directory written as `!<pattern>`, excluding matching folders or files.
+    pub excludes: Vec<String>,
+    /// Tag queries written as `? <query>`, selecting matching albums from the whole source directory.
+    pub queries: Vec<Query>,
+    /// The entries of `paths`, to skip duplicates without scanning the whole list.
+    listed: HashSet<PathBuf>,
+}
+
+impl SyncList {
+    /// Whether the list only excludes, in which case everything else in the source directory is selected.
+    pub fn selects_everything(&self) -> bool {
+        self.paths.is_empty() && self.queries.is_empty()
+    }
+
+    /// Adds a folder or file to sync, unless it is already listed.
+    pub fn add_path(&mut self, path: PathBuf) {
+        if self.listed.insert(path.clone()) {
+            self.paths.push(path);
+        }
+    }
+
+    /// The position of the first listed entry containing `file`, ranking tracks by the order of the list.
+    pub fn position(&self, file: &Path) -> Option<usize> {
+        self.paths.iter().position(|x| file.starts_with(x))
+    }
+
+    /// Whether a path relative to the source directory, or one of its parent folders, is excluded.
+    pub fn is_excluded(&self, rel_path: &Path) -> bool {
+        let rel_path = rel_path.to_string_lossy().replace('\\', "/");
+        let mut prefix = String::with_capacity(rel_path.len());
+
+        rel_path.split('/').any(|component| {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(component);
+
+            self.excludes.iter().any(|x| glob::matches_path(x, &prefix))
+        })
+    }
+}
+
+/// Parses a sync list, where each line is one of:
+///
+/// - `Artist/Album`: a folder or file relative to the source directory, `~` and absolute paths within it are allowed.
+/// - `Various Artists/Stream Palette *`: a glob pattern matched against the source directory.
+/// - `!Artist/Live Bootlegs`: an exclusion, taking precedence over everything else.
+/// - `? genre = Jazz`: a tag query.
+/// - `%include other.txt`: another sync list, resolved relative to the including list.
+/// - `# comment`
+pub fn parse_sync_list(source_dir: &Path, path: &Path) -> Result<SyncList> {
+    let mut list = SyncList::default();
+    let mut stack = Vec::new();
+    read_sync_list(source_dir, path, &mut list, &mut stack)?;
+
+    Ok(list)
+}
+
+fn read_sync_list(source_dir: &Path, path: &Path, list: &mut SyncList, stack: &mut Vec<PathBuf>) -> Result<()> {
+    let contents = std::fs::read_to_string(path).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))?;
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| Error::from(e).with_context(path.to_string_lossy()))?;
+
+    if stack.contains(&canonical) {
+        return Err(Error::descriptive("Sync list includes itself").with_context(path.to_string_lossy()));
+    }
+    stack.push(canonical);
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        let at = || format!("{}:{}", path.display(), i + 1);
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(include) = line
+            .strip_prefix("%include")
+            .filter(|x| x.starts_with(char::is_whitespace))
+        {
+            let include = path.parent().unwrap_or(Path::new("")).join(expand_home(include.trim()));
+            if !include.is_file() {
+                let message = format!("Included sync list {include:?} does not exist");
+                return Err(Error::descriptive(message).with_context(at()));
+            }
+
+            read_sync_list(source_dir, &include, list, stack)?;
+        } else if let Some(query) = line.strip_prefix('?') {
+            let query = query
+                .parse::<Query>()
+                .map_err(|e| Error::descriptive(format!("Invalid query: {e}")).with_context(at()))?;
+            list.queries.push(query);
+        } else if let Some(exclude) = line.strip_prefix('!') {
+            let exclude = relative_entry(source_dir, exclude.trim()).map_err(|e| e.with_context(at()))?;
+            list.excludes.push(exclude);
+        } else {
+            let entry = relative_entry(source_dir, line).map_err(|e| e.with_context(at()))?;
+            let literal = source_dir.join(&entry);
+
+            // Folders like `Album [Deluxe]` are taken as they are, only entries naming nothing are expanded as globs.
+            if literal.exists() {
+                list.add_path(literal);
+            } else if glob::is_glob(&entry) {
+                let matched = glob::expand(source_dir, &entry)?;
+                if matched.is_empty() {
+                    let message = format!("'{line}' does not match anything in the source directory");
+                    return Err(Error::descriptive(message).with_context(at()));
+                }
+
+                for path in matched {
+                    list.add_path(path);
+                }
+            } else {
+                let message = format!("'{line}' does not exist in the source directory");
+                return Err(Error::descriptive(message).with_context(at()));
+            }
+        }
+    }
+
+    stack.pop();
+    Ok(())
+}
+
+/// Writes a path relative to the source directory as a sync list entry, prefixing `./` when it would otherwise be read
+/// as a comment, exclusion, query, include or home directory.
+pub fn sync_list_entry(rel_path: &Path) -> String {
+    let entry = rel_path.to_string_lossy().replace('\\', "/");
+    if entry.starts_with(['#', '!', '?', '%', '~']) || entry.starts_with(char::is_whitespace) {
+        format!("./{entry}")
+    } else {
+        entry
+    }
+}
+
+/// Turns a sync list entry into a `/` separated path relative to the source directory.
+fn relative_entry(source_dir: &Path, entry: &str) -> Result<String> {
+    let entry = expand_home(entry);
+    let relative = if entry.is_absolute() {
+        let abs_source_dir = normalize_path(&std::path::absolute(source_dir)?);
+        normalize_path(&entry)
+            .strip_prefix(&abs_source_dir)
+            .map(Path::to_path_buf)
+            .map_err(|_| Error::descriptive(format!("{entry:?} is outside of the source directory")))?
+    } else {
+        normalize_path(&entry)
+    };
+
+    if relative.starts_with("..") {
+        return Err(Error::descriptive(format!(
+            "{entry:?} is outside of the source directory"
+        )));
+    }
+
+    Ok(relative.to_string_lossy().replace('\\', "/"))
+}
+
+/// Collects the tracks referenced by a playlist, returning them alongside the entries that could not be found.
+///
+/// Relative entries are resolved against the playlist's location first, then against `source_dir`.
+/// Tracks outside of `source_dir` are treated as missing.
+pub fn parse_sync_playlist(source_dir: &Path, path: &Path) -> Result<(HashSet<PathBuf>, Vec<String>)> {
+    let playlist = Playlist::read(path)?;
+    let abs_source_dir = normalize_path(&std::path::absolute(source_dir)?);
+    let abs_playlist = normalize_path(&std::path::absolute(path)?);
 
-    let splits = contents
-        .split(&['\n', '\r'])
-        .map(str::trim)
-        .filter(|x| !x.is_empty() && !x.starts_with('#'))
-        .map(|x| source_dir.join(x))
-        .collect::<HashSet<_>>();
+    let mut tracks = HashSet::new();
+    let mut missing = Vec::new();
 
-    Ok(splits)
+    for entry in &playlist.entries {
+        let location = PathBuf::from(entry.location.replace('\\', "/"));
+        let relative_to_source = location
+            .is_relative()
+            .then(|| normalize_path(&abs_source_dir.join(&location)));
+
+        let found = Playlist::resolve_entry(&abs_playlist, entry)
+            .into_iter()
+            .chain(relative_to_source)
+            .filter(|x| x.is_file())
+            .find_map(|x| x.strip_prefix(&abs_source_dir).ok().map(|rel| source_dir.join(rel)));
+
+        match found {
+            Some(track) => {
+                tracks.insert(track);
+            }
+            None => missing.push(entry.location.clone()),
+        }
+    }
+
+    Ok((tracks, missing))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parse_sync_list;
+    use super::{parse_sync_list, parse_sync_playlist, sync_list_entry};
     use std::{
         fs,
-        path::PathBuf,
+        path::{Path, PathBuf},
         time::{SystemTime, UNIX_EPOCH},
     };
 
@@ -44,7 +234,9 @@ mod tests {
         let source = unique_temp_path("source");
         let sync_list_file = unique_temp_path("sync-list.txt");
 
-        fs::create_dir_all(&source).expect("source dir should be created");
+        for album in ["Album A", "Album B", "Album C"] {
+            fs::create_dir_all(source.join(album)).expect("album dir should be created");
+        }
         fs::write(
             &sync_list_file,
             "# comment\nAlbum A\r\n\nAlbum B\n   # another comment\nAlbum C\n",
@@ -53,12 +245,119 @@ mod tests {
 
         let parsed = parse_sync_list(&source, &sync_list_file).expect("sync list should parse");
 
-        assert_eq!(parsed.len(), 3);
-        assert!(parsed.contains(&source.join("Album A")));
-        assert!(parsed.contains(&source.join("Album B")));
-        assert!(parsed.contains(&source.join("Album C")));
+        assert_eq!(parsed.paths.len(), 3);
+        assert!(parsed.paths.contains(&source.join("Album A")));
+        assert!(parsed.paths.contains(&source.join("Album B")));
+        assert!(parsed.paths.contains(&source.join("Album C")));
+
+        let _ = fs::remove_file(sync_list_file);
+        let _ = fs::remove_dir_all(source);
+    }
+
+    #[test]
+    fn parse_sync_list_expands_globs_exclusions_and_includes() {
+        let source = unique_temp_path("v2-source");
+        let lists = unique_temp_path("v2-lists");
+
+        for album in [
+            "Stream Palette 4",
+            "Stream Palette 5 -RANKED-",
+            "Stream Palette Live",
+            "Other",
+        ] {
+            fs::create_dir_all(source.join("Various Artists").join(album)).expect("album dir should be created");
+        }
+        fs::create_dir_all(source.join("ESAI")).expect("artist dir should be created");
+        fs::create_dir_all(source.join("Album [Deluxe]")).expect("album dir should be created");
+        fs::create_dir_all(source.join("What?")).expect("album dir should be created");
+        fs::create_dir_all(source.join("%includes")).expect("album dir should be created");
+        fs::create_dir_all(&lists).expect("lists dir should be created");
+
+        fs::write(lists.join("extra.txt"), "ESAI\n").expect("included list should be written");
+        fs::write(
+            lists.join("main.txt"),
+            format!(
+                "Various Artists/Stream Palette *\n!Various Artists/* Live\n%include extra.txt\n{}\nAlbum [Deluxe]\nWhat?\n%includes\n",
+                source.join("Various Artists/Other").display()
+            ),
+        )
+        .expect("sync list should be written");
+
+        let parsed = parse_sync_list(&source, &lists.join("main.txt")).expect("sync list should parse");
+        let various = source.join("Various Artists");
+
+        assert_eq!(parsed.paths.len(), 8);
+        assert!(parsed.paths.contains(&source.join("Album [Deluxe]")));
+        assert!(parsed.paths.contains(&source.join("%includes")));
+        assert!(parsed.paths.contains(&source.join("What?")));
+        assert!(parsed.paths.contains(&various.join("Stream Palette 5 -RANKED-")));
+        assert!(parsed.paths.contains(&various.join("Other")));
+        assert!(parsed.paths.contains(&source.join("ESAI")));
+        assert!(parsed.is_excluded(Path::new("Various Artists/Stream Palette Live/01.flac")));
+        assert!(!parsed.is_excluded(Path::new("Various Artists/Stream Palette 4/01.flac")));
+
+        fs::write(lists.join("broken.txt"), "ESAI\n\nK03\n").expect("sync list should be written");
+        let error = parse_sync_list(&source, &lists.join("broken.txt")).expect_err("missing entry should fail");
+        assert!(error.to_string().ends_with("broken.txt:3"));
+
+        let _ = fs::remove_dir_all(source);
+        let _ = fs::remove_dir_all(lists);
+    }
+
+    #[test]
+    fn sync_list_entries_are_read_back_literally() {
+        let source = unique_temp_path("entry-source");
+        let sync_list_file = unique_temp_path("entry-sync-list.txt");
+        let names = ["!Live", "?", "%include x", "#1 Hits", "~", " Intro", "Album"];
+
+        for name in names {
+            fs::create_dir_all(source.join(name)).expect("album dir should be created");
+        }
+        let contents = names
+            .iter()
+            .map(|x| format!("{}\n", sync_list_entry(Path::new(x))))
+            .collect::<String>();
+        fs::write(&sync_list_file, contents).expect("sync list should be written");
+
+        let parsed = parse_sync_list(&source, &sync_list_file).expect("sync list should parse");
+
+        assert_eq!(parsed.paths, names.map(|x| source.join(x)));
+        assert!(parsed.excludes.is_empty() && parsed.queries.is_empty());
 
         let _ = fs::remove_file(sync_list_file);
         let _ = fs::remove_dir_all(source);
     }
+
+    #[test]
+    fn parse_sync_playlist_resolves_entries_and_reports_missing() {
+        let source = unique_temp_path("playlist-source");
+        let album = source.join("Artist").join("Album");
+        let playlist_file = source.join("Playlists").join("mix.m3u8");
+
+        fs::create_dir_all(&album).expect("album dir should be created");
+        fs::create_dir_all(playlist_file.parent().unwrap()).expect("playlist dir should be created");
+        for name in ["01.flac", "02.flac", "03.flac"] {
+            fs::write(album.join(name), b"").expect("track should be written");
+        }
+
+        let absolute = album.join("03.flac");
+        fs::write(
+            &playlist_file,
+            format!(
+                "#EXTM3U\n../Artist/Album/01.flac\nArtist/Album/02.flac\n{}\nArtist/Album/04.flac\n",
+                absolute.display()
+            ),
+        )
+        .expect("playlist should be written");
+
+        let (tracks, missing) = parse_sync_playlist(&source, &playlist_file).expect("playlist should parse");
+
+        assert_eq!(tracks.len(), 3);
+        assert!(tracks.contains(&album.join("01.flac")));
+        assert!(tracks.contains(&album.join("02.flac")));
+        assert!(tracks.contains(&album.join("03.flac")));
+        assert_eq!(missing, vec!["Artist/Album/04.flac".to_string()]);
+
+        let _ = fs::remove_dir_all(source);
+    }
 }
@@ -2,27 +2,38 @@ use std::{path::Path, process::Command};
 
 use crate::{
     errors::{Error, Result},
-    format::Codec,
+    format::{
+        Codec, SourceFormat,
+        encoder::{Encoder, Resample},
+    },
 };
 
-pub fn transcode_file<P: AsRef<Path>>(source: P, target: P, codec: Codec, bitrate: u32) -> Result<()> {
-    let output = match codec {
-        Codec::Opus => Command::new("opusenc")
-            .arg("--bitrate")
-            .arg(format!("{}K", bitrate))
-            .arg(source.as_ref())
-            .arg(target.as_ref())
-            .output(),
-        _ => Command::new("ffmpeg")
-            .arg("-i")
-            .arg(source.as_ref())
-            .arg("-c:a")
-            .arg(codec.ffmpeg_lib())
-            .arg("-b:a")
-            .arg(format!("{}K", bitrate))
-            .arg(target.as_ref())
-            .output(),
-    }?;
+/// The audio to read from a source file.
+#[derive(Debug, Clone, Copy)]
+pub struct TranscodeSource<'a> {
+    pub path: &'a Path,
+    pub format: SourceFormat,
+    /// The audio stream to read, counted among the audio streams of the file like ffmpeg's `0:a:N`.
+    pub audio_track: usize,
+    /// The start and length in seconds, to cut a track out of a longer file like an album image.
+    pub range: Option<(f64, Option<f64>)>,
+}
+
+/// Transcodes `source` into `target`, writing any `metadata` key/value pairs on top of the tags carried over from the
+/// source file.
+///
+/// Opus is encoded with opusenc when it can read the source (FLAC, WAV and AIFF) as a whole, and with ffmpeg otherwise.
+/// `resample` is only honoured by ffmpeg, opusenc always resamples to 48kHz on its own. A `volume` change in dB is
+/// applied with ffmpeg as well, so it is used whenever one is set.
+pub fn transcode_file(
+    source: &TranscodeSource,
+    target: &Path,
+    encoder: &Encoder,
+    resample: Option<&Resample>,
+    volume: Option<f64>,
+    metadata: &[(&str, &str)],
+) -> Result<()> {
+    let output = transcode_command(source, target, encoder, resample, volume, metadata).output()?;
 
     if !output.status.success() {
         let message = format!("transcoder exited with code {}", output.status.code().unwrap_or(-1));
@@ -32,3 +43,101 @@ pub fn transcode_file<P: AsRef<Path>>(source: P, target: P, codec: Codec, bitrat
 
     Ok(())
 }
+
+/// Builds the opusenc or ffmpeg invocation of [`transcode_file`].
+fn transcode_command(
+    source: &TranscodeSource,
+    target: &Path,
+    encoder: &Encoder,
+    resample: Option<&Resample>,
+    volume: Option<f64>,
+    metadata: &[(&str, &str)],
+) -> Command {
+    let is_whole_file = source.audio_track == 0 && source.range.is_none();
+    match encoder.codec() {
+        Codec::Opus
+            if is_whole_file && volume.is_none() && matches!(source.format, SourceFormat::Flac | SourceFormat::Pcm) =>
+        {
+            let mut cmd = Command::new("opusenc");
+            cmd.args(encoder.opusenc_args());
+
+            for (key, value) in metadata {
+                cmd.arg("--comment").arg(format!("{}={}", key.to_uppercase(), value));
+            }
+
+            cmd.arg(source.path).arg(target);
+            cmd
+        }
+        _ => {
+            let mut cmd = Command::new("ffmpeg");
+            if let Some((start, _)) = source.range {
+                cmd.arg("-ss").arg(format!("{start:.6}"));
+            }
+
+            cmd.arg("-i").arg(source.path);
+            if let Some((_, Some(duration))) = source.range {
+                cmd.arg("-t").arg(format!("{duration:.6}"));
+            }
+            // Only the audio stream is mapped, ffmpeg would otherwise pick up cover art or video streams as well.
+            cmd.arg("-map").arg(format!("0:a:{}", source.audio_track));
+
+            cmd.args(encoder.ffmpeg_args());
+
+            let mut filters = vec![];
+            if let Some(volume) = volume {
+                filters.push(format!("volume={volume:.2}dB"));
+            }
+            if let Some(resample) = resample {
+                filters.push(resample.ffmpeg_filter());
+                cmd.args(resample.ffmpeg_args());
+            }
+            if !filters.is_empty() {
+                cmd.arg("-af").arg(filters.join(","));
+            }
+
+            for (key, value) in metadata {
+                cmd.arg("-metadata").arg(format!("{key}={value}"));
+            }
+
+            cmd.arg(target);
+            cmd
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{TranscodeSource, transcode_command};
+    use crate::format::{Codec, SourceFormat, encoder::Encoder};
+
+    fn command_line(source: &TranscodeSource, codec: Codec, metadata: &[(&str, &str)]) -> (String, Vec<String>) {
+        let encoder = Encoder::new(codec, None, None, None).expect("encoder should be valid");
+        let cmd = transcode_command(source, Path::new("out"), &encoder, None, None, metadata);
+        let args = cmd.get_args().map(|x| x.to_string_lossy().to_string()).collect();
+        (cmd.get_program().to_string_lossy().to_string(), args)
+    }
+
+    #[test]
+    fn passes_lyrics_as_metadata() {
+        let mut source = TranscodeSource {
+            path: Path::new("track.flac"),
+            format: SourceFormat::Flac,
+            audio_track: 0,
+            range: None,
+        };
+        let metadata = [("lyrics", "[00:01.00]Line")];
+
+        let (program, args) = command_line(&source, Codec::Opus, &metadata);
+        assert_eq!(program, "opusenc");
+        assert!(args.windows(2).any(|x| x == ["--comment", "LYRICS=[00:01.00]Line"]));
+
+        source.format = SourceFormat::Alac;
+        let (program, args) = command_line(&source, Codec::Opus, &metadata);
+        assert_eq!(program, "ffmpeg");
+        assert!(args.windows(2).any(|x| x == ["-metadata", "lyrics=[00:01.00]Line"]));
+        assert!(args.windows(2).any(|x| x == ["-map", "0:a:0"]));
+        assert_eq!(args.last().map(String::as_str), Some("out"));
+    }
+}
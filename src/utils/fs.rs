@@ -1,27 +1,54 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     io::BufRead,
     path::{Path, PathBuf},
     process::Command,
+    sync::OnceLock,
 };
 
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
-use crate::errors::{Error, Result};
+use crate::{
+    errors::{Error, Result},
+    utils::ignore::Ignore,
+};
 
 struct BackendADB;
 struct BackendNone;
 
+/// The size and modification time of a file, used to tell whether a copy is up to date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub modified: u64,
+}
+
 trait FSEmu {
     fn available() -> Result<bool>;
     fn build_file_list(source: &Path) -> Result<HashSet<PathBuf>>;
+    fn build_dir_list(source: &Path) -> Result<HashSet<PathBuf>>;
+    fn build_file_stats(source: &Path) -> Result<HashMap<PathBuf, FileStat>>;
     fn cp(source: &Path, target: &Path) -> Result<()>;
+    /// Copies `source` on the backend to the local `target`, keeping its modification time.
+    fn cp_from(source: &Path, target: &Path) -> Result<()>;
     fn exists(source: &Path) -> Result<bool>;
+    fn remove(target: &Path) -> Result<()>;
+    /// Removes an empty directory.
+    fn rmdir(target: &Path) -> Result<()>;
+    /// The bytes available to unprivileged users on the filesystem holding `path`, or `None` if it can't be told.
+    fn free_space(path: &Path) -> Result<Option<u64>>;
 }
 
-#[derive(Debug, Clone, ValueEnum)]
+/// The serial passed to every adb invocation, see [`FSBackend::select_device`].
+static ADB_SERIAL: OnceLock<String> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum FSBackend {
     /// Useful for android devices connected over tcpip or usb, and is recommended for all android-targeted syncs.
+    #[default]
     Adb,
 
     /// Reserved for future FTP backend support.
@@ -32,7 +59,87 @@ pub enum FSBackend {
     None,
 }
 
+/// A device listed by `adb devices -l`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdbDevice {
+    pub serial: String,
+    /// `device` once usable, `unauthorized` until USB debugging is allowed on the device, or `offline`.
+    pub state: String,
+    pub model: Option<String>,
+}
+
+/// A storage volume of an ADB device.
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub path: PathBuf,
+    /// Whether the volume is an SD card or USB drive rather than the internal storage.
+    pub removable: bool,
+    pub free_space: Option<u64>,
+}
+
 impl FSBackend {
+    /// Lists the devices known to the ADB server, whatever their state.
+    pub fn adb_devices() -> Result<Vec<AdbDevice>> {
+        let output = match Command::new("adb").arg("devices").arg("-l").output() {
+            Ok(output) => output,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::descriptive(
+                    "adb was not found, install the Android SDK Platform-Tools and add them to the PATH",
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !output.status.success() {
+            let message = format!(
+                "adb devices failed with code {}: {}",
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return Err(Error::descriptive(message));
+        }
+
+        Ok(parse_devices(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// The Android version of an ADB device, e.g. `14`.
+    pub fn android_version(serial: &str) -> Result<String> {
+        let output = BackendADB::shell_on(Some(serial), &["getprop", "ro.build.version.release"])?;
+        Ok(String::from_utf8_lossy(&output).trim().to_string())
+    }
+
+    /// Lists the internal storage of an ADB device and the SD cards mounted under `/storage/XXXX-XXXX`.
+    ///
+    /// Volumes `df` can't be run on, like SD cards the shell isn't allowed to read, are listed without their free space.
+    pub fn volumes(serial: &str) -> Result<Vec<Volume>> {
+        let output = BackendADB::shell_on(Some(serial), &["ls", "/storage"])?;
+        let removable = String::from_utf8_lossy(&output)
+            .split_whitespace()
+            .filter(|x| is_volume_id(x))
+            .map(|x| (Path::new("/storage").join(x), true))
+            .collect::<Vec<_>>();
+
+        let volumes = std::iter::once((PathBuf::from("/storage/emulated/0"), false))
+            .chain(removable)
+            .map(|(path, removable)| {
+                let free_space = BackendADB::shell_on(Some(serial), &["df", "-k", &BackendADB::quote(&path)])
+                    .ok()
+                    .and_then(|x| parse_df(&String::from_utf8_lossy(&x)));
+                Volume {
+                    path,
+                    removable,
+                    free_space,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Ok(volumes)
+    }
+
+    /// Targets the ADB device with the given serial instead of the only connected one.
+    pub fn select_device(serial: &str) {
+        let _ = ADB_SERIAL.set(serial.to_string());
+    }
+
     pub fn available(&self) -> Result<bool> {
         match self {
             FSBackend::Adb => BackendADB::available(),
@@ -49,6 +156,38 @@ impl FSBackend {
         }
     }
 
+    /// Lists every directory below `source`, excluding `source` itself.
+    pub fn build_dir_list(&self, source: &Path) -> Result<HashSet<PathBuf>> {
+        match self {
+            FSBackend::Adb => BackendADB::build_dir_list(source),
+            FSBackend::Ftp => Err(Error::descriptive("FTP backend is not implemented yet")),
+            FSBackend::None => BackendNone::build_dir_list(source),
+        }
+    }
+
+    /// Lists every file below `source` with its size and modification time.
+    pub fn build_file_stats(&self, source: &Path) -> Result<HashMap<PathBuf, FileStat>> {
+        match self {
+            FSBackend::Adb => BackendADB::build_file_stats(source),
+            FSBackend::Ftp => Err(Error::descriptive("FTP backend is not implemented yet")),
+            FSBackend::None => BackendNone::build_file_stats(source),
+        }
+    }
+
+    /// Copies `source` on the backend to the local `target`, creating its parent directories and keeping the
+    /// modification time of `source`.
+    pub fn cp_from(&self, source: &Path, target: &Path) -> Result<()> {
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        match self {
+            FSBackend::Adb => BackendADB::cp_from(source, target),
+            FSBackend::Ftp => Err(Error::descriptive("FTP backend is not implemented yet")),
+            FSBackend::None => BackendNone::cp_from(source, target),
+        }
+    }
+
     pub fn cp(&self, source: &Path, target: &Path) -> Result<()> {
         match self {
             FSBackend::Adb => BackendADB::cp(source, target),
@@ -64,6 +203,43 @@ impl FSBackend {
             FSBackend::None => BackendNone::exists(source),
         }
     }
+
+    pub fn remove(&self, target: &Path) -> Result<()> {
+        match self {
+            FSBackend::Adb => BackendADB::remove(target),
+            FSBackend::Ftp => Err(Error::descriptive("FTP backend is not implemented yet")),
+            FSBackend::None => BackendNone::remove(target),
+        }
+    }
+
+    /// Removes an empty directory, failing if it still has entries.
+    pub fn rmdir(&self, target: &Path) -> Result<()> {
+        match self {
+            FSBackend::Adb => BackendADB::rmdir(target),
+            FSBackend::Ftp => Err(Error::descriptive("FTP backend is not implemented yet")),
+            FSBackend::None => BackendNone::rmdir(target),
+        }
+    }
+
+    /// The free space of the filesystem `target` will be written to, measured at its closest existing ancestor.
+    pub fn free_space(&self, target: &Path) -> Result<Option<u64>> {
+        let mut existing = None;
+        for path in target.ancestors().filter(|x| !x.as_os_str().is_empty()) {
+            if self.exists(path)? {
+                existing = Some(path);
+                break;
+            }
+        }
+        let Some(path) = existing else {
+            return Ok(None);
+        };
+
+        match self {
+            FSBackend::Adb => BackendADB::free_space(path),
+            FSBackend::Ftp => Err(Error::descriptive("FTP backend is not implemented yet")),
+            FSBackend::None => BackendNone::free_space(path),
+        }
+    }
 }
 
 impl FSEmu for BackendNone {
@@ -89,6 +265,40 @@ impl FSEmu for BackendNone {
         Ok(files)
     }
 
+    fn build_dir_list(source: &Path) -> Result<HashSet<PathBuf>> {
+        let mut dirs = HashSet::new();
+        for entry in std::fs::read_dir(source)? {
+            let path = entry?.path();
+
+            if path.is_dir() {
+                dirs.extend(Self::build_dir_list(&path)?);
+                dirs.insert(path);
+            }
+        }
+
+        Ok(dirs)
+    }
+
+    fn build_file_stats(source: &Path) -> Result<HashMap<PathBuf, FileStat>> {
+        let mut stats = HashMap::new();
+        for file in Self::build_file_list(source)? {
+            let stat = file_stat(&std::fs::metadata(&file)?);
+            stats.insert(file, stat);
+        }
+
+        Ok(stats)
+    }
+
+    fn cp_from(source: &Path, target: &Path) -> Result<()> {
+        use std::fs;
+
+        fs::copy(source, target)?;
+        let modified = fs::metadata(source)?.modified()?;
+        fs::File::options().write(true).open(target)?.set_modified(modified)?;
+
+        Ok(())
+    }
+
     fn cp(source: &Path, target: &Path) -> Result<()> {
         use std::{fs, io::ErrorKind};
 
@@ -111,10 +321,89 @@ impl FSEmu for BackendNone {
     fn exists(source: &Path) -> Result<bool> {
         Ok(source.try_exists()?)
     }
+
+    fn remove(target: &Path) -> Result<()> {
+        Ok(std::fs::remove_file(target)?)
+    }
+
+    fn rmdir(target: &Path) -> Result<()> {
+        Ok(std::fs::remove_dir(target)?)
+    }
+
+    #[cfg(unix)]
+    fn free_space(path: &Path) -> Result<Option<u64>> {
+        use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| Error::descriptive("Path contains a null byte").with_context(path.to_string_lossy()))?;
+        let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+        // SAFETY: `path` is a valid C string and `stat` is only read after statvfs succeeds and has filled it.
+        if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let stat = unsafe { stat.assume_init() };
+
+        #[allow(clippy::unnecessary_cast)]
+        Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+
+    #[cfg(not(unix))]
+    fn free_space(_: &Path) -> Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+impl BackendADB {
+    fn command() -> Command {
+        Self::command_on(ADB_SERIAL.get().map(String::as_str))
+    }
+
+    fn command_on(serial: Option<&str>) -> Command {
+        let mut cmd = Command::new("adb");
+        if let Some(serial) = serial {
+            cmd.arg("-s").arg(serial);
+        }
+
+        cmd
+    }
+
+    /// Runs a shell command on the device, failing with its stderr if it exits unsuccessfully.
+    fn shell(args: &[&str]) -> Result<Vec<u8>> {
+        Self::shell_on(ADB_SERIAL.get().map(String::as_str), args)
+    }
+
+    fn shell_on(serial: Option<&str>, args: &[&str]) -> Result<Vec<u8>> {
+        let output = Self::command_on(serial).arg("shell").args(args).output()?;
+
+        if !output.status.success() {
+            let message = format!(
+                "adb {} failed with code {}: {}",
+                args.first().unwrap_or(&""),
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return Err(Error::descriptive(message));
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// Quotes a path for the device's shell, which adb passes the arguments to as a single command line.
+    ///
+    /// Single quotes keep `$`, `` ` `` and `"` in file names literal, quotes within the path are written as `'\''`.
+    fn quote(path: &Path) -> String {
+        format!("'{}'", path.to_string_lossy().replace('\\', "/").replace('\'', r"'\''"))
+    }
 }
 
 impl FSEmu for BackendADB {
     fn available() -> Result<bool> {
+        if ADB_SERIAL.get().is_some() {
+            let output = Self::command().arg("get-state").output()?;
+            return Ok(output.status.success() && String::from_utf8_lossy(&output.stdout).trim() == "device");
+        }
+
         let is_adb_running = Command::new("adb")
             .arg("devices")
             .output()
@@ -125,37 +414,71 @@ impl FSEmu for BackendADB {
 
     fn build_file_list(source: &Path) -> Result<HashSet<PathBuf>> {
         let mut files = HashSet::new();
-        let path_str = source.to_string_lossy().replace('\\', "/");
-        let output = Command::new("adb")
-            .arg("shell")
-            .arg("find")
-            .arg(&path_str)
-            .arg("-type")
-            .arg("f")
-            .output()?;
+        let output = Self::shell(&["find", &Self::quote(source), "-type", "f"])
+            .map_err(|e| e.with_context(source.to_string_lossy()))?;
+
+        for line in output.lines() {
+            let line = line?;
+            files.insert(PathBuf::from(line));
+        }
+
+        Ok(files)
+    }
+
+    fn build_dir_list(source: &Path) -> Result<HashSet<PathBuf>> {
+        let source_str = source.to_string_lossy().replace('\\', "/");
+        let output = Self::shell(&["find", &Self::quote(source), "-mindepth", "1", "-type", "d"])
+            .map_err(|e| e.with_context(source_str))?;
 
+        let mut dirs = HashSet::new();
+        for line in output.lines() {
+            dirs.insert(PathBuf::from(line?));
+        }
+
+        Ok(dirs)
+    }
+
+    fn build_file_stats(source: &Path) -> Result<HashMap<PathBuf, FileStat>> {
+        let source_str = source.to_string_lossy().replace('\\', "/");
+        let args = [
+            "find",
+            &Self::quote(source),
+            "-type",
+            "f",
+            "-exec",
+            "stat",
+            "-c",
+            "'%s %Y %n'",
+            "{}",
+            "+",
+        ];
+        let output = Self::shell(&args).map_err(|e| e.with_context(source_str))?;
+
+        Ok(parse_stats(&String::from_utf8_lossy(&output)))
+    }
+
+    fn cp_from(source: &Path, target: &Path) -> Result<()> {
+        let source = source.to_string_lossy().replace('\\', "/");
+
+        // `-a` keeps the modification time, which tells later pulls that the file is up to date.
+        let output = Self::command().arg("pull").arg("-a").arg(source).arg(target).output()?;
         if !output.status.success() {
             let message = format!(
-                "adb find failed with code {}: {}",
+                "adb exited with code {} detailing {}",
                 output.status.code().unwrap_or(-1),
                 String::from_utf8_lossy(&output.stderr)
             );
             return Err(Error::descriptive(message));
         }
 
-        for line in output.stdout.lines() {
-            let line = line?;
-            files.insert(PathBuf::from(line));
-        }
-
-        Ok(files)
+        Ok(())
     }
 
     fn cp(source: &Path, target: &Path) -> Result<()> {
         let source = source.to_string_lossy().replace('\\', "/");
         let target = target.to_string_lossy().replace('\\', "/");
 
-        let mut cmd = Command::new("adb");
+        let mut cmd = Self::command();
         cmd.arg("push").arg(source).arg(target);
 
         let output = cmd.output()?;
@@ -173,15 +496,101 @@ impl FSEmu for BackendADB {
     }
 
     fn exists(source: &Path) -> Result<bool> {
-        // For some reason adb shell only accepts "escaped paths", like path/dir/location.opus -> "path/dir/location" with string quotes
-        let path = format!(r#""{}""#, source.to_string_lossy().replace('\\', "/"));
-        let output = Command::new("adb").arg("shell").arg("ls").arg(path).output()?;
+        Ok(Self::shell(&["ls", &Self::quote(source)]).is_ok())
+    }
+
+    fn remove(target: &Path) -> Result<()> {
+        Self::shell(&["rm", &Self::quote(target)]).map_err(|e| e.with_context(target.to_string_lossy()))?;
+        Ok(())
+    }
+
+    fn rmdir(target: &Path) -> Result<()> {
+        Self::shell(&["rmdir", &Self::quote(target)]).map_err(|e| e.with_context(target.to_string_lossy()))?;
+        Ok(())
+    }
+
+    fn free_space(path: &Path) -> Result<Option<u64>> {
+        let output = Self::shell(&["df", "-k", &Self::quote(path)])?;
+        Ok(parse_df(&String::from_utf8_lossy(&output)))
+    }
+}
 
-        Ok(output.status.success())
+/// Reads the size and modification time of local files.
+pub fn file_stat(metadata: &std::fs::Metadata) -> FileStat {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+
+    FileStat {
+        size: metadata.len(),
+        modified,
     }
 }
 
-pub fn read_dir_recursively<P: AsRef<Path>>(path: P, extensions: &Option<Vec<&'static str>>) -> Result<Vec<PathBuf>> {
+/// Reads the output of `stat -c '%s %Y %n'`, one `<size> <modified> <path>` line per file.
+fn parse_stats(output: &str) -> HashMap<PathBuf, FileStat> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ' ');
+            let size = parts.next()?.parse().ok()?;
+            let modified = parts.next()?.parse().ok()?;
+            let path = parts.next().filter(|x| !x.is_empty())?;
+
+            Some((PathBuf::from(path), FileStat { size, modified }))
+        })
+        .collect()
+}
+
+/// Reads the available bytes from the output of `df -k <path>`.
+///
+/// Long filesystem names are put on a line of their own by some `df` implementations, so the fields are read across
+/// every line after the header.
+fn parse_df(output: &str) -> Option<u64> {
+    let available = output.lines().skip(1).flat_map(str::split_whitespace).nth(3)?;
+    available.parse::<u64>().ok().map(|x| x * 1024)
+}
+
+/// Reads the output of `adb devices -l`, skipping its header and the messages of a starting server.
+fn parse_devices(output: &str) -> Vec<AdbDevice> {
+    output
+        .lines()
+        .filter(|x| !x.starts_with("List of devices") && !x.starts_with('*'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let serial = fields.next()?.to_string();
+            let state = fields.next()?.to_string();
+            let model = fields
+                .find_map(|x| x.strip_prefix("model:"))
+                .map(|x| x.replace('_', " "));
+
+            Some(AdbDevice { serial, state, model })
+        })
+        .collect()
+}
+
+/// Matches the `XXXX-XXXX` names of the SD cards and USB drives mounted under `/storage`.
+fn is_volume_id(name: &str) -> bool {
+    name.split_once('-').is_some_and(|(a, b)| {
+        [a, b]
+            .iter()
+            .all(|x| x.len() == 4 && x.chars().all(|c| c.is_ascii_hexdigit()))
+    })
+}
+
+/// Matches extensions case-insensitively, as libraries ripped on other systems tend to have `.FLAC` or `.Mp3` files.
+fn has_extension(extensions: &[&str], ext: &str) -> bool {
+    extensions.iter().any(|x| x.eq_ignore_ascii_case(ext))
+}
+
+pub fn read_dir_recursively<P: AsRef<Path>>(
+    path: P,
+    extensions: &Option<Vec<&'static str>>,
+    ignore: &Ignore,
+) -> Result<Vec<PathBuf>> {
     let mut files = Vec::<PathBuf>::new();
 
     for entry in std::fs::read_dir(path)? {
@@ -189,7 +598,11 @@ pub fn read_dir_recursively<P: AsRef<Path>>(path: P, extensions: &Option<Vec<&'s
         let path = entry.path();
 
         if path.is_dir() {
-            let mut sub_files = read_dir_recursively(path, extensions)?;
+            if ignore.is_ignored(&path, true) {
+                continue;
+            }
+
+            let mut sub_files = read_dir_recursively(&path, extensions, &ignore.enter(&path)?)?;
             files.append(&mut sub_files);
             continue;
         }
@@ -199,8 +612,8 @@ pub fn read_dir_recursively<P: AsRef<Path>>(path: P, extensions: &Option<Vec<&'s
             None => continue,
         };
         match extensions {
-            Some(exts) if exts.contains(&ext) => files.push(path),
-            None => files.push(path),
+            Some(exts) if has_extension(exts, ext) && !ignore.is_ignored(&path, false) => files.push(path),
+            None if !ignore.is_ignored(&path, false) => files.push(path),
             _ => continue,
         }
     }
@@ -208,12 +621,14 @@ pub fn read_dir_recursively<P: AsRef<Path>>(path: P, extensions: &Option<Vec<&'s
     Ok(files)
 }
 
-pub fn read_selectively<P, I>(paths: I, extensions: &Option<Vec<&'static str>>) -> Result<Vec<PathBuf>>
+pub fn read_selectively<P, I>(paths: I, extensions: &Option<Vec<&'static str>>, ignore: &Ignore) -> Result<Vec<PathBuf>>
 where
     P: AsRef<Path>,
     I: IntoIterator<Item = P>,
 {
     let mut files = Vec::<PathBuf>::new();
+    // Selections built from playlists list many tracks of the same folders.
+    let mut ignore_cache = HashMap::new();
 
     for entry in paths {
         let path = entry.as_ref();
@@ -222,8 +637,14 @@ where
             return Err(Error::descriptive("File does not exist").with_context(path.to_string_lossy()));
         }
 
+        // Explicitly listed paths are still subject to the ignore files of their parent folders.
+        let ignore = ignore.at_cached(path, &mut ignore_cache)?;
+        if ignore.is_ignored_within(path) {
+            continue;
+        }
+
         if path.is_dir() {
-            let mut sub_files = read_dir_recursively(path, extensions)?;
+            let mut sub_files = read_dir_recursively(path, extensions, &ignore)?;
             files.append(&mut sub_files);
             continue;
         }
@@ -233,7 +654,7 @@ where
             None => continue,
         };
         match extensions {
-            Some(exts) if exts.contains(&ext) => files.push(path.to_path_buf()),
+            Some(exts) if has_extension(exts, ext) => files.push(path.to_path_buf()),
             None => files.push(path.to_path_buf()),
             _ => continue,
         }
@@ -241,3 +662,121 @@ where
 
     Ok(files)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AdbDevice, BackendADB, FileStat, is_volume_id, parse_devices, parse_df, parse_stats};
+    use std::path::Path;
+
+    #[test]
+    fn parses_df_output() {
+        let toybox = "Filesystem      1K-blocks     Used Available Use% Mounted on
+/dev/fuse       115249236 60122836  55126400  53% /storage/emulated
+";
+        assert_eq!(parse_df(toybox), Some(55126400 * 1024));
+
+        let wrapped = "Filesystem           1K-blocks      Used Available Use% Mounted on
+/dev/block/mapper/userdata-encrypted
+                      60817412  41234567  19582845  68% /data
+";
+        assert_eq!(parse_df(wrapped), Some(19582845 * 1024));
+        assert_eq!(parse_df("df: /sdcard/Music: No such file or directory"), None);
+    }
+
+    #[test]
+    fn quotes_paths_for_the_device_shell() {
+        assert_eq!(
+            BackendADB::quote(Path::new("/sdcard/Music/Guns N' Roses/$1 \"Hit\".opus")),
+            r#"'/sdcard/Music/Guns N'\'' Roses/$1 "Hit".opus'"#
+        );
+    }
+
+    /// Runs a command the way `adb shell` does, joining the arguments into one command line for the shell.
+    #[cfg(unix)]
+    fn device_shell(args: &[&str]) -> std::process::Output {
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(args.join(" "))
+            .output()
+            .expect("sh should run")
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn quoted_paths_survive_the_device_shell() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("system time should be after unix epoch")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("tsync-test-quote-{nanos}/Guns N' Roses/$HOME `id` \"Live\""));
+        let file = dir.join("01 Intro.opus");
+        std::fs::create_dir_all(&dir).expect("album dir should be created");
+        std::fs::write(&file, b"").expect("track should be written");
+
+        assert!(device_shell(&["ls", &BackendADB::quote(&file)]).status.success());
+        let found = device_shell(&["find", &BackendADB::quote(&dir), "-type", "f"]);
+        assert_eq!(String::from_utf8_lossy(&found.stdout).trim(), file.to_string_lossy());
+        assert!(device_shell(&["rm", &BackendADB::quote(&file)]).status.success());
+        assert!(!file.exists());
+
+        let _ = std::fs::remove_dir_all(dir.ancestors().nth(2).expect("test root should exist"));
+    }
+
+    #[test]
+    fn parses_adb_devices() {
+        let devices = parse_devices(
+            "* daemon not running; starting now at tcp:5037
+* daemon started successfully
+List of devices attached
+1A2B3C4D               device usb:1-1 product:panther model:Pixel_7 device:panther transport_id:1
+R58M12ABCDE            unauthorized usb:1-2 transport_id:2
+emulator-5554          offline
+
+",
+        );
+
+        assert_eq!(
+            devices,
+            [
+                AdbDevice {
+                    serial: "1A2B3C4D".to_string(),
+                    state: "device".to_string(),
+                    model: Some("Pixel 7".to_string())
+                },
+                AdbDevice {
+                    serial: "R58M12ABCDE".to_string(),
+                    state: "unauthorized".to_string(),
+                    model: None
+                },
+                AdbDevice {
+                    serial: "emulator-5554".to_string(),
+                    state: "offline".to_string(),
+                    model: None
+                },
+            ]
+        );
+
+        assert!(is_volume_id("1A2B-3C4D"));
+        assert!(!is_volume_id("emulated"));
+        assert!(!is_volume_id("self"));
+    }
+
+    #[test]
+    fn parses_stat_output() {
+        let stats = parse_stats(
+            "1204 1717171717 /sdcard/Playlists/Road Trip.m3u8
+88 1700000000 /sdcard/Recordings/memo 1.m4a
+stat: /sdcard/x: Permission denied
+",
+        );
+
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            stats.get(Path::new("/sdcard/Playlists/Road Trip.m3u8")),
+            Some(&FileStat {
+                size: 1204,
+                modified: 1717171717
+            })
+        );
+    }
+}
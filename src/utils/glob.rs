@@ -0,0 +1,223 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use crate::errors::Result;
+
+/// Returns true if `pattern` contains any glob metacharacters.
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Matches `text` against a glob `pattern` where `*` matches any sequence of characters.
+///
+/// Supports `*`, `?`, character classes like `[abc]`, `[a-z]` and `[!abc]`, and `\` escapes.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    Matcher::new(pattern, text, false).matches()
+}
+
+/// Matches a `/` separated `path` against a glob `pattern`, where `*` and `?` never match a `/` and `**` matches
+/// across any number of directories.
+pub fn matches_path(pattern: &str, path: &str) -> bool {
+    Matcher::new(pattern, path, true).matches()
+}
+
+/// Expands a `/` separated glob `pattern` relative to `base` into the existing paths it matches.
+pub fn expand(base: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let components = pattern.split('/').filter(|x| !x.is_empty()).collect::<Vec<_>>();
+    let mut matched = Vec::new();
+    expand_components(base, &components, &mut matched)?;
+
+    Ok(matched)
+}
+
+fn expand_components(base: &Path, components: &[&str], matched: &mut Vec<PathBuf>) -> Result<()> {
+    let Some((&component, rest)) = components.split_first() else {
+        matched.push(base.to_path_buf());
+        return Ok(());
+    };
+
+    if component == "**" {
+        expand_components(base, rest, matched)?;
+    } else if !is_glob(component) {
+        let path = base.join(component);
+        if path.exists() {
+            expand_components(&path, rest, matched)?;
+        }
+
+        return Ok(());
+    }
+
+    if !base.is_dir() {
+        return Ok(());
+    }
+
+    let mut entries = std::fs::read_dir(base)?.collect::<std::result::Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|x| x.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        if component == "**" {
+            if path.is_dir() {
+                expand_components(&path, components, matched)?;
+            }
+        } else if matches(component, &entry.file_name().to_string_lossy()) {
+            expand_components(&path, rest, matched)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Matches a pattern against a text by backtracking over the wildcards, remembering the positions that already
+/// failed so that patterns with several `*` stay linear in the length of the text for each of them.
+struct Matcher {
+    pattern: Vec<char>,
+    text: Vec<char>,
+    path_mode: bool,
+    /// Pattern and text positions known not to match.
+    failed: HashSet<(usize, usize)>,
+}
+
+impl Matcher {
+    fn new(pattern: &str, text: &str, path_mode: bool) -> Matcher {
+        Matcher {
+            pattern: pattern.chars().collect(),
+            text: text.chars().collect(),
+            path_mode,
+            failed: HashSet::new(),
+        }
+    }
+
+    fn matches(&mut self) -> bool {
+        self.match_from(0, 0)
+    }
+
+    fn match_from(&mut self, p: usize, t: usize) -> bool {
+        if self.failed.contains(&(p, t)) {
+            return false;
+        }
+
+        let matched = self.match_step(p, t);
+        if !matched {
+            self.failed.insert((p, t));
+        }
+
+        matched
+    }
+
+    fn match_step(&mut self, p: usize, t: usize) -> bool {
+        let path_mode = self.path_mode;
+        let Some(&first) = self.pattern.get(p) else {
+            return t == self.text.len();
+        };
+
+        match first {
+            '*' if path_mode && self.pattern.get(p + 1) == Some(&'*') => {
+                // `**/` also matches zero directories, e.g. `**/cover.jpg` matches `cover.jpg`.
+                if self.pattern.get(p + 2) == Some(&'/') && self.match_from(p + 3, t) {
+                    return true;
+                }
+
+                (t..=self.text.len()).any(|i| self.match_from(p + 2, i))
+            }
+            '*' => {
+                for i in t..=self.text.len() {
+                    if self.match_from(p + 1, i) {
+                        return true;
+                    }
+                    if path_mode && self.text.get(i) == Some(&'/') {
+                        break;
+                    }
+                }
+
+                false
+            }
+            '?' => match self.text.get(t) {
+                Some('/') if path_mode => false,
+                Some(_) => self.match_from(p + 1, t + 1),
+                None => false,
+            },
+            '[' => {
+                let Some(&c) = self.text.get(t) else {
+                    return false;
+                };
+
+                match match_class(&self.pattern[p + 1..], c) {
+                    Some((true, consumed)) if !(path_mode && c == '/') => self.match_from(p + 1 + consumed, t + 1),
+                    Some(_) => false,
+                    // An unterminated class is matched literally.
+                    None => c == '[' && self.match_from(p + 1, t + 1),
+                }
+            }
+            '\\' if p + 1 < self.pattern.len() => {
+                self.text.get(t) == Some(&self.pattern[p + 1]) && self.match_from(p + 2, t + 1)
+            }
+            literal => self.text.get(t) == Some(&literal) && self.match_from(p + 1, t + 1),
+        }
+    }
+}
+
+/// Matches `c` against the class body following a `[`, returning whether it matched and how many pattern characters
+/// the class consumed, including the closing `]`.
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some('!') | Some('^'));
+    let mut i = usize::from(negated);
+    let mut matched = false;
+    let mut first = true;
+
+    while i < class.len() {
+        let current = class[i];
+        if current == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+
+        if class.get(i + 1) == Some(&'-') && class.get(i + 2).is_some_and(|x| *x != ']') {
+            matched |= (current..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= current == c;
+            i += 1;
+        }
+
+        first = false;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches, matches_path};
+
+    #[test]
+    fn matches_wildcards_and_classes() {
+        assert!(matches("Stream Palette *", "Stream Palette 4"));
+        assert!(matches("*instrumental*", "01 Song (instrumental).flac"));
+        assert!(matches("track0?.flac", "track07.flac"));
+        assert!(matches("[!a-c]*", "dune"));
+        assert!(!matches("[!a-c]*", "beach"));
+        assert!(!matches("Stream Palette ?", "Stream Palette 10"));
+    }
+
+    #[test]
+    fn matches_path_respects_separators() {
+        assert!(matches_path(
+            "Various Artists/Stream Palette *",
+            "Various Artists/Stream Palette 5 -RANKED-"
+        ));
+        assert!(!matches_path("*/cover.jpg", "Artist/Album/cover.jpg"));
+        assert!(matches_path("**/cover.jpg", "Artist/Album/cover.jpg"));
+        assert!(matches_path("**/cover.jpg", "cover.jpg"));
+        assert!(matches_path("Artist/**", "Artist/Album/01.flac"));
+    }
+
+    #[test]
+    fn matches_many_wildcards_quickly() {
+        let text = "a".repeat(200);
+        assert!(!matches("*a*a*a*a*a*a*a*a*a*a*b", &text));
+        assert!(!matches_path("**/*a*a*a*a*a*a*a*a*b", &format!("x/{text}")));
+        assert!(matches("*a*a*a*a*a*a*a*a*a*a", &text));
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    errors::{Error, Result},
+    utils::glob,
+};
+
+pub const IGNORE_FILE_NAME: &str = ".tsyncignore";
+
+#[derive(Debug)]
+struct IgnoreRule {
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    /// Patterns containing a `/` are matched against the path relative to the ignore file, others against the name.
+    anchored: bool,
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> Option<IgnoreRule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let pattern = line.strip_prefix('/').unwrap_or(line).to_string();
+
+        if pattern.is_empty() {
+            return None;
+        }
+
+        Some(IgnoreRule {
+            pattern,
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, rel_path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        if self.anchored {
+            glob::matches_path(&self.pattern, rel_path)
+        } else {
+            let name = rel_path.rsplit('/').next().unwrap_or(rel_path);
+            glob::matches(&self.pattern, name)
+        }
+    }
+}
+
+/// Rules read from a single ignore file, or passed on the command line, relative to `base`.
+#[derive(Debug)]
+struct IgnoreFile {
+    base: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreFile {
+    fn new<'a, I>(base: &Path, lines: I) -> IgnoreFile
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        IgnoreFile {
+            base: base.to_path_buf(),
+            rules: lines.into_iter().filter_map(IgnoreRule::parse).collect(),
+        }
+    }
+
+    fn read(dir: &Path) -> Result<Option<IgnoreFile>> {
+        let path = dir.join(IGNORE_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let contents =
+            std::fs::read_to_string(&path).map_err(|e| Error::from(e).with_context(path.to_string_lossy()))?;
+        Ok(Some(IgnoreFile::new(dir, contents.lines())))
+    }
+
+    /// Returns whether the last rule matching `path` ignores or re-includes it, or `None` if no rule matches.
+    fn decide(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let rel_path = path.strip_prefix(&self.base).ok()?.to_string_lossy().replace('\\', "/");
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(&rel_path, is_dir))
+            .map(|rule| !rule.negated)
+    }
+}
+
+/// Exclusions from `--exclude` patterns and `.tsyncignore` files, following gitignore semantics.
+///
+/// Ignore files apply to the folder they're in and everything below it, deeper files taking precedence.
+/// Command line patterns are relative to the root and always exclude, regardless of negations in ignore files.
+#[derive(Debug, Clone, Default)]
+pub struct Ignore {
+    root: PathBuf,
+    cli: Option<Arc<IgnoreFile>>,
+    files: Vec<Arc<IgnoreFile>>,
+}
+
+impl Ignore {
+    pub fn new(root: &Path, patterns: &[String]) -> Result<Ignore> {
+        let cli = (!patterns.is_empty()).then(|| Arc::new(IgnoreFile::new(root, patterns.iter().map(String::as_str))));
+        let files = IgnoreFile::read(root)?.map(Arc::new).into_iter().collect();
+
+        Ok(Ignore {
+            root: root.to_path_buf(),
+            cli,
+            files,
+        })
+    }
+
+    /// Returns the rules applying within `dir`, a direct child of the folder these rules were built for.
+    pub fn enter(&self, dir: &Path) -> Result<Ignore> {
+        let mut ignore = self.clone();
+        if let Some(file) = IgnoreFile::read(dir)? {
+            ignore.files.push(Arc::new(file));
+        }
+
+        Ok(ignore)
+    }
+
+    /// Returns the rules applying to `path`, reading the ignore files of every folder between the root and it.
+    ///
+    /// The rules of the folders entered are kept in `cache`, so that the ignore files of a folder are read once for
+    /// all the paths within it.
+    pub fn at_cached(&self, path: &Path, cache: &mut HashMap<PathBuf, Ignore>) -> Result<Ignore> {
+        let dir = if path.is_dir() {
+            path
+        } else {
+            path.parent().unwrap_or(path)
+        };
+
+        self.dir_rules(dir, cache)
+    }
+
+    fn dir_rules(&self, dir: &Path, cache: &mut HashMap<PathBuf, Ignore>) -> Result<Ignore> {
+        if dir == self.root || !dir.starts_with(&self.root) {
+            return Ok(self.clone());
+        }
+        if let Some(ignore) = cache.get(dir) {
+            return Ok(ignore.clone());
+        }
+
+        let parent = dir.parent().unwrap_or(&self.root);
+        let ignore = self.dir_rules(parent, cache)?.enter(dir)?;
+        cache.insert(dir.to_path_buf(), ignore.clone());
+
+        Ok(ignore)
+    }
+
+    /// Whether `path`, or any folder between the root and it, is ignored.
+    pub fn is_ignored_within(&self, path: &Path) -> bool {
+        let Ok(rel_path) = path.strip_prefix(&self.root) else {
+            return self.is_ignored(path, path.is_dir());
+        };
+
+        let components = rel_path.components().collect::<Vec<_>>();
+        let mut current = self.root.clone();
+
+        components.iter().enumerate().any(|(i, component)| {
+            current.push(component);
+            let is_dir = i + 1 < components.len() || path.is_dir();
+            self.is_ignored(&current, is_dir)
+        })
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if self.cli.as_ref().is_some_and(|x| x.decide(path, is_dir) == Some(true)) {
+            return true;
+        }
+
+        self.files
+            .iter()
+            .rev()
+            .find_map(|file| file.decide(path, is_dir))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IgnoreFile;
+    use std::path::Path;
+
+    #[test]
+    fn follows_gitignore_semantics() {
+        let root = Path::new("/music");
+        let file = IgnoreFile::new(
+            root,
+            [
+                "# comment",
+                "Bootlegs/",
+                "*instrumental*",
+                "/Scratch",
+                "!keep (instrumental).flac",
+                "Artist/*.wav",
+            ],
+        );
+
+        assert_eq!(file.decide(&root.join("A/Bootlegs"), true), Some(true));
+        assert_eq!(file.decide(&root.join("A/Bootlegs"), false), None);
+        assert_eq!(
+            file.decide(&root.join("A/B/01 Song (instrumental).flac"), false),
+            Some(true)
+        );
+        assert_eq!(
+            file.decide(&root.join("A/keep (instrumental).flac"), false),
+            Some(false)
+        );
+        assert_eq!(file.decide(&root.join("Scratch"), true), Some(true));
+        assert_eq!(file.decide(&root.join("A/Scratch"), true), None);
+        assert_eq!(file.decide(&root.join("Artist/01.wav"), false), Some(true));
+        assert_eq!(file.decide(&root.join("Other/Artist/01.wav"), false), None);
+    }
+}
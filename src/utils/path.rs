@@ -1,7 +1,10 @@
+use std::path::{Component, Path, PathBuf};
+
 pub trait PathExtensions {
     fn get_file_name(&self) -> String;
     fn get_file_ext(&self) -> Option<String>;
     fn is_extra(&self) -> bool;
+    fn get_lyrics_sidecar(&self) -> Option<PathBuf>;
 }
 
 impl PathExtensions for std::path::Path {
@@ -26,4 +29,98 @@ impl PathExtensions for std::path::Path {
             )
         })
     }
+
+    /// Looks for a `.lrc` file sharing the same stem, e.g. `01 Track.flac` -> `01 Track.lrc`.
+    fn get_lyrics_sidecar(&self) -> Option<PathBuf> {
+        ["lrc", "LRC"]
+            .into_iter()
+            .map(|ext| self.with_extension(ext))
+            .find(|path| path.is_file())
+    }
+}
+
+/// Lexically resolves `.` and `..` components without touching the filesystem.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            other => normalized.push(other),
+        }
+    }
+
+    normalized
+}
+
+/// Computes the path of `target` relative to the directory `base`, both being relative to the same root.
+pub fn relative_path(base: &Path, target: &Path) -> PathBuf {
+    let base = normalize_path(base);
+    let target = normalize_path(target);
+
+    let common = base
+        .components()
+        .zip(target.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+    for component in target.components().skip(common) {
+        relative.push(component);
+    }
+
+    relative
+}
+
+/// Expands a leading `~` to the user's home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+
+    match (path, home) {
+        ("~", Some(home)) => PathBuf::from(home),
+        (path, Some(home)) if path.starts_with("~/") => PathBuf::from(home).join(&path[2..]),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::PathExtensions;
+
+    #[test]
+    fn finds_lyrics_sidecars() {
+        let dir = std::env::temp_dir().join(format!("tsync-lyrics-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("test dir should be created");
+        for name in [
+            "01 Track.flac",
+            "01 Track.lrc",
+            "02 Track.flac",
+            "02 Track.LRC",
+            "03 Track.flac",
+        ] {
+            fs::write(dir.join(name), b"").expect("test file should be written");
+        }
+
+        assert_eq!(
+            dir.join("01 Track.flac").get_lyrics_sidecar(),
+            Some(dir.join("01 Track.lrc"))
+        );
+        assert_eq!(
+            dir.join("02 Track.flac").get_lyrics_sidecar(),
+            Some(dir.join("02 Track.LRC"))
+        );
+        assert_eq!(dir.join("03 Track.flac").get_lyrics_sidecar(), None);
+
+        let _ = fs::remove_dir_all(dir);
+    }
 }
@@ -0,0 +1,210 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use colored::*;
+use notify::{
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{AccessKind, AccessMode},
+};
+
+use crate::errors::{Error, Result};
+
+/// Files changed in the source directory, grouped by their album folder.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// The last time each album folder changed.
+    albums: HashMap<PathBuf, Instant>,
+    /// The last time each file changed.
+    files: HashMap<PathBuf, Instant>,
+}
+
+impl Changes {
+    /// Records a change to the file at `path`, counted towards the folder holding it.
+    pub fn add(&mut self, path: &Path, now: Instant) {
+        let album = path.parent().unwrap_or(path);
+        self.albums.insert(album.to_path_buf(), now);
+        self.files.insert(path.to_path_buf(), now);
+    }
+
+    /// Records a change to the folder itself, like it being created, holding it back until it settles.
+    pub fn add_album(&mut self, path: &Path, now: Instant) {
+        self.albums.insert(path.to_path_buf(), now);
+    }
+
+    /// The album folders that haven't changed for `settle`, sorted.
+    pub fn settled(&self, settle: Duration, now: Instant) -> Vec<PathBuf> {
+        let mut albums = self
+            .albums
+            .iter()
+            .filter(|(_, changed)| now.duration_since(**changed) >= settle)
+            .map(|(album, _)| album.clone())
+            .collect::<Vec<_>>();
+        albums.sort();
+        albums
+    }
+
+    /// The album folders still changing, whose files shouldn't be synced yet.
+    pub fn pending(&self, settle: Duration, now: Instant) -> Vec<PathBuf> {
+        self.albums
+            .iter()
+            .filter(|(_, changed)| now.duration_since(**changed) < settle)
+            .map(|(album, _)| album.clone())
+            .collect()
+    }
+
+    /// The changed files directly within `albums`.
+    pub fn files_in(&self, albums: &[PathBuf]) -> HashSet<PathBuf> {
+        self.files
+            .keys()
+            .filter(|x| {
+                x.parent()
+                    .is_some_and(|parent| albums.iter().any(|album| album == parent))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets the changes made to `albums` up to `until`, keeping the ones seen while they were synced.
+    pub fn clear(&mut self, albums: &[PathBuf], until: Instant) {
+        let is_synced = |path: &Path, changed: &Instant| *changed <= until && albums.iter().any(|x| x == path);
+
+        self.albums.retain(|album, changed| !is_synced(album, changed));
+        self.files
+            .retain(|file, changed| !is_synced(file.parent().unwrap_or(file), changed));
+    }
+}
+
+/// Watches the source directory recursively, collecting the changes made to it.
+pub struct LibraryWatcher {
+    // Stops watching when dropped.
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    pub changes: Changes,
+}
+
+impl LibraryWatcher {
+    pub fn new(source_dir: &Path) -> Result<LibraryWatcher> {
+        let (tx, events) = mpsc::channel();
+        let watch_error = |e: notify::Error| {
+            Error::descriptive(format!("Couldn't watch the source directory: {e}"))
+                .with_context(source_dir.to_string_lossy())
+        };
+
+        let mut watcher = notify::recommended_watcher(tx).map_err(watch_error)?;
+        watcher
+            .watch(source_dir, RecursiveMode::Recursive)
+            .map_err(watch_error)?;
+
+        Ok(LibraryWatcher {
+            _watcher: watcher,
+            events,
+            changes: Changes::default(),
+        })
+    }
+
+    /// Waits up to `timeout` for changes, then collects every change reported in the meantime.
+    pub fn wait(&mut self, timeout: Duration) -> Result<()> {
+        let mut event = match self.events.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::descriptive("Stopped receiving changes of the source directory"));
+            }
+        };
+
+        while let Some(result) = event {
+            match result {
+                Ok(event) if is_change(&event.kind) => {
+                    let now = Instant::now();
+                    for path in &event.paths {
+                        if path.is_dir() {
+                            self.changes.add_album(path, now);
+                        } else {
+                            self.changes.add(path, now);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => println!("{} {e}", "Couldn't watch a change:".yellow()),
+            }
+
+            event = self.events.try_recv().ok();
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether an event changes the contents of the library, as opposed to files merely being read.
+fn is_change(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Access(_) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    use super::Changes;
+
+    #[test]
+    fn settles_albums_separately() {
+        let settle = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut changes = Changes::default();
+
+        changes.add(&PathBuf::from("/lib/A/Album 1/01.flac"), start);
+        changes.add(
+            &PathBuf::from("/lib/A/Album 2/01.flac"),
+            start + Duration::from_secs(20),
+        );
+        changes.add(
+            &PathBuf::from("/lib/A/Album 1/02.flac"),
+            start + Duration::from_secs(10),
+        );
+
+        let now = start + Duration::from_secs(45);
+        let settled = changes.settled(settle, now);
+        assert_eq!(settled, vec![PathBuf::from("/lib/A/Album 1")]);
+        assert_eq!(changes.pending(settle, now), vec![PathBuf::from("/lib/A/Album 2")]);
+
+        assert_eq!(
+            changes.files_in(&settled),
+            HashSet::from([
+                PathBuf::from("/lib/A/Album 1/01.flac"),
+                PathBuf::from("/lib/A/Album 1/02.flac")
+            ])
+        );
+
+        // A change seen while the album was synced keeps it around for the next sync.
+        changes.add(
+            &PathBuf::from("/lib/A/Album 1/03.flac"),
+            start + Duration::from_secs(50),
+        );
+        changes.clear(&settled, now);
+        assert_eq!(
+            changes.files_in(&settled),
+            HashSet::from([PathBuf::from("/lib/A/Album 1/03.flac")])
+        );
+        assert_eq!(
+            changes.settled(settle, now + Duration::from_secs(10)),
+            vec![PathBuf::from("/lib/A/Album 2")]
+        );
+
+        changes.clear(&settled, start + Duration::from_secs(50));
+        changes.clear(&[PathBuf::from("/lib/A/Album 2")], now);
+        assert!(changes.settled(settle, now + settle).is_empty());
+        assert!(changes.files_in(&[PathBuf::from("/lib/A/Album 2")]).is_empty());
+    }
+}